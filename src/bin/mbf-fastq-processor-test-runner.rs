@@ -234,13 +234,13 @@ fn discover_test_cases_recursive(dir: &Path, test_cases: &mut Vec<TestCase>) ->
     Ok(())
 }
 
-fn read_compressed(filename: impl AsRef<Path>) -> Result<String> {
+fn read_compressed(filename: impl AsRef<Path>) -> Result<Vec<u8>> {
     let fh = std::fs::File::open(filename.as_ref())
         .with_context(|| format!("Could not open file {:?}", filename.as_ref()))?;
     let mut wrapped = niffler::send::get_reader(Box::new(fh))?;
     let mut out: Vec<u8> = Vec::new();
     wrapped.0.read_to_end(&mut out)?;
-    Ok(std::str::from_utf8(&out)?.to_string())
+    Ok(out)
 }
 
 struct TestOutput {
@@ -363,11 +363,18 @@ fn perform_test(
         }
     }
 
-    let proc = std::process::Command::new(processor_cmd)
+    let mut command = std::process::Command::new(processor_cmd);
+    command
         .arg(&config_file)
         .arg(temp_dir.path())
         .env("NO_FRIENDLY_PANIC","1")
-        .current_dir(temp_dir.path())
+        .current_dir(temp_dir.path());
+    //tests may provide a 'stdin' file that's piped into the processor
+    let stdin_file = test_case.dir.join("stdin");
+    if stdin_file.exists() {
+        command.stdin(fs::File::open(&stdin_file).context("Failed to open stdin file")?);
+    }
+    let proc = command
         .output()
         .context(format!("Failed to run {CLI_UNDER_TEST}"))?;
 
@@ -430,7 +437,7 @@ fn perform_test(
                     //if compressed, compare uncompressed
                     if expected_path
                         .extension()
-                        .map_or(false, |ext| ext == "gz" || ext == "zst")
+                        .map_or(false, |ext| ext == "gz" || ext == "zst" || ext == "bam")
                     {
                         let expected_uncompressed = read_compressed(&expected_path)?;
                         let actual_uncompressed = read_compressed(&path)?;
@@ -2,18 +2,27 @@ use crate::transformations::{Step, Transformation};
 use anyhow::{bail, Context, Result};
 use serde_valid::Validate;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
 };
 
 pub mod deser;
 
-use deser::{string_or_seq_string, string_or_seq_string_or_none};
+use deser::{string_or_seq_string, string_or_seq_string_or_none, u8_from_char_or_number};
 
 fn default_true() -> bool {
     true
 }
 
+fn default_fasta_fake_quality() -> u8 {
+    b'I' // phred 40
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)] // serde's skip_serializing_if passes a reference
+fn is_default_fasta_fake_quality(value: &u8) -> bool {
+    *value == default_fasta_fake_quality()
+}
+
 #[derive(serde::Deserialize, Debug, Clone, serde::Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Input {
@@ -39,8 +48,59 @@ pub struct Input {
     pub index2: Option<Vec<String>>,
     #[serde(default)]
     pub interleaved: bool,
+    #[serde(default, skip_serializing_if = "InputFormat::is_auto")]
+    pub format: InputFormat,
+    /// quality assigned to every base of FASTA reads
+    /// (and BAM reads without qualities)
+    #[serde(
+        default = "default_fasta_fake_quality",
+        deserialize_with = "u8_from_char_or_number",
+        skip_serializing_if = "is_default_fasta_fake_quality"
+    )]
+    pub fasta_fake_quality: u8,
+    /// BAM aux tags (e.g. 'BC', 'RX') to turn into tags of the same name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bam_import_tags: Vec<String>,
+}
+
+impl Input {
+    /// read2 is either separate files, or in the interleaved read1 files.
+    #[must_use]
+    pub fn has_read2(&self) -> bool {
+        self.read2.is_some() || self.interleaved
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// decide per file, based on the first byte ('>' is FASTA, everything else FASTQ)
+    #[serde(alias = "auto")]
+    #[default]
+    Auto,
+    #[serde(alias = "fastq")]
+    #[serde(alias = "FASTQ")]
+    Fastq,
+    #[serde(alias = "fasta")]
+    #[serde(alias = "FASTA")]
+    Fasta,
+    /// unaligned BAM or SAM (decided on the file extension).
+    /// Chosen by Auto if all read1 files end in .bam / .sam
+    #[serde(alias = "bam")]
+    #[serde(alias = "BAM")]
+    #[serde(alias = "sam")]
+    #[serde(alias = "SAM")]
+    Bam,
 }
 
+impl InputFormat {
+    #[allow(clippy::trivially_copy_pass_by_ref)] // serde's skip_serializing_if passes a reference
+    fn is_auto(&self) -> bool {
+        *self == InputFormat::Auto
+    }
+}
+
+/// What `output.format` writes: FASTQ (uncompressed or compressed), FASTA, BAM or nothing.
+/// The compression of fastq/fasta files is a `CompressionFormat`, see `Output::compression_format`
 #[derive(serde::Deserialize, Debug, Copy, Clone, Default)]
 pub enum FileFormat {
     #[serde(alias = "raw")]
@@ -59,8 +119,51 @@ pub enum FileFormat {
     #[serde(alias = "none")] // we need this so you can disable the output, but set a prefix for
     // the Reports
     None,
+    /// unaligned BAM, read1 & read2 in one file
+    #[serde(alias = "bam")]
+    #[serde(alias = "BAM")]
+    #[serde(alias = "uBAM")]
+    Bam,
+    /// FASTA, no qualities. Compression is set via output.compression
+    #[serde(alias = "fasta")]
+    #[serde(alias = "FASTA")]
+    #[serde(alias = "fa")]
+    Fasta,
+}
+
+/// How we compress the files we write.
+/// Accepts the same names as `FileFormat`, but nothing else.
+#[derive(serde::Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[serde(try_from = "FileFormat")]
+pub enum CompressionFormat {
+    #[default]
+    Raw,
+    Gzip,
+    Zstd,
+}
+
+impl TryFrom<FileFormat> for CompressionFormat {
+    type Error = String;
+
+    fn try_from(value: FileFormat) -> std::result::Result<Self, Self::Error> {
+        match value {
+            FileFormat::Raw => Ok(CompressionFormat::Raw),
+            FileFormat::Gzip => Ok(CompressionFormat::Gzip),
+            FileFormat::Zstd => Ok(CompressionFormat::Zstd),
+            FileFormat::None => Err(
+                "'None' (no output) is not a compression. Use 'raw' to get uncompressed data."
+                    .to_string(),
+            ),
+            FileFormat::Bam | FileFormat::Fasta => Err(format!(
+                "'{value:?}' is not a compression. Use 'raw', 'gzip' or 'zstd'."
+            )),
+        }
+    }
 }
 
+/// BAM aux field holding the step that removed a read in the rejected output
+pub const REJECTED_BY_BAM_TAG: [u8; 2] = *b"XR";
+
 #[allow(clippy::struct_excessive_bools)]
 #[derive(serde::Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
@@ -69,7 +172,11 @@ pub struct Output {
     pub suffix: Option<String>,
     #[serde(default)]
     pub format: FileFormat,
+    /// gzip: 0..=9, zstd: 1..=22. Defaults to 6 / 5
     pub compression_level: Option<u8>,
+    /// zstd long distance matching (like zstd --long)
+    #[serde(default)]
+    pub zstd_long: bool,
 
     #[serde(default)]
     pub report_html: bool,
@@ -93,6 +200,21 @@ pub struct Output {
 
     #[serde(default)]
     pub output_hash: bool,
+
+    /// write molecules removed by filters to {prefix}_rejected_*,
+    /// with 'rejected_by=<step>' appended to the read names
+    /// (BAM: in the `REJECTED_BY_BAM_TAG` aux field)
+    #[serde(default)]
+    pub output_rejected: bool,
+
+    /// tag label -> SAM aux tag (e.g. 'RX'), for format = 'BAM'
+    #[serde(default)]
+    pub bam_tags: BTreeMap<String, String>,
+
+    /// Raw, Gzip or Zstd - for format = 'FASTA'
+    pub compression: Option<CompressionFormat>,
+    /// wrap FASTA sequences after this many bases. Default: no wrapping
+    pub fasta_line_width: Option<usize>,
 }
 
 impl Output {
@@ -105,9 +227,70 @@ impl Output {
                 FileFormat::Gzip => "fq.gz",
                 FileFormat::Zstd => "fq.zst",
                 FileFormat::None => "",
+                FileFormat::Bam => "bam",
+                FileFormat::Fasta => match self.compression_format() {
+                    CompressionFormat::Raw => "fa",
+                    CompressionFormat::Gzip => "fa.gz",
+                    CompressionFormat::Zstd => "fa.zst",
+                },
             })
             .to_string()
     }
+
+    /// The compression of the fastq/fasta files we're writing.
+    /// Raw for BAM (which brings its own) and 'None' output.
+    #[must_use]
+    pub fn compression_format(&self) -> CompressionFormat {
+        match self.format {
+            FileFormat::Raw | FileFormat::None | FileFormat::Bam => CompressionFormat::Raw,
+            FileFormat::Gzip => CompressionFormat::Gzip,
+            FileFormat::Zstd => CompressionFormat::Zstd,
+            FileFormat::Fasta => self.compression.unwrap_or_default(),
+        }
+    }
+
+    /// The compression level actually used (None for uncompressed output)
+    #[must_use]
+    pub fn effective_compression_level(&self) -> Option<u8> {
+        effective_compression_level(self.compression_format(), self.compression_level)
+    }
+}
+
+#[must_use]
+pub fn effective_compression_level(
+    format: CompressionFormat,
+    compression_level: Option<u8>,
+) -> Option<u8> {
+    match format {
+        CompressionFormat::Raw => None,
+        CompressionFormat::Gzip => Some(compression_level.unwrap_or(6)),
+        CompressionFormat::Zstd => Some(compression_level.unwrap_or(5)),
+    }
+}
+
+/// Check a compression level against the (compression) format it's used with
+pub fn validate_compression_level(
+    format: CompressionFormat,
+    compression_level: Option<u8>,
+) -> Result<()> {
+    if let Some(level) = compression_level {
+        match format {
+            CompressionFormat::Gzip => {
+                if level > 9 {
+                    bail!("Compression level for gzip must be between 0 and 9, was {level}.");
+                }
+            }
+            CompressionFormat::Zstd => {
+                if !(1..=22).contains(&level) {
+                    bail!("Compression level for zstd must be between 1 and 22, was {level}.");
+                }
+            }
+            CompressionFormat::Raw => {
+                bail!("compression_level is only supported for gzip and zstd compressed output.");
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(serde::Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
@@ -159,6 +342,20 @@ impl Display for TargetPlusAll {
     }
 }
 
+/// How the per-read filters treat read pairs (instead of a single target)
+#[derive(serde::Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PairedEndPolicy {
+    /// keep the molecule only if both reads pass
+    #[serde(alias = "remove_pair")]
+    RemovePair,
+    /// keep the molecule if at least one read passes
+    #[serde(alias = "keep_pair_if_any_passes")]
+    KeepPairIfAnyPasses,
+    /// like RemovePair, but the passing mate goes to the {prefix}_singletons output
+    #[serde(alias = "emit_orphans")]
+    EmitOrphans,
+}
+
 #[derive(serde::Deserialize, Debug, Clone, Validate)]
 #[serde(deny_unknown_fields)]
 pub struct RegionDefinition {
@@ -181,6 +378,14 @@ fn default_output_buffer_size() -> usize {
     1024 * 1024 // bytes, per fastq input file
 }
 
+fn default_decompression_thread_count() -> usize {
+    1 // per BGZF input file
+}
+
+fn default_compression_thread_count() -> usize {
+    1 // shared by all gzip/zstd output files. > 1 turns gzip output into BGZF
+}
+
 fn default_block_size() -> usize {
     //todo: adjust depending on compression mode?
     10000 // in 'molecules', ie. read1, read2, index1, index2 tuples.
@@ -199,6 +404,10 @@ pub struct Options {
     pub output_buffer_size: usize,
     #[serde(default)]
     pub accept_duplicate_files: bool,
+    #[serde(default = "default_decompression_thread_count")]
+    pub decompression_thread_count: usize,
+    #[serde(default = "default_compression_thread_count")]
+    pub compression_thread_count: usize,
 }
 
 impl Default for Options {
@@ -209,6 +418,8 @@ impl Default for Options {
             buffer_size: default_buffer_size(),
             output_buffer_size: default_output_buffer_size(),
             accept_duplicate_files: false,
+            decompression_thread_count: default_decompression_thread_count(),
+            compression_thread_count: default_compression_thread_count(),
         }
     }
 }
@@ -232,6 +443,7 @@ impl Config {
         if no_of_files == 0 {
             bail!("No read1 files specified / empty list.");
         }
+        self.check_stdin_input()?;
         let mut seen = HashSet::new();
         if !self.options.accept_duplicate_files {
             for f in &self.input.read1 {
@@ -262,7 +474,7 @@ impl Config {
                 }
             }
         } else if let Some(output) = &self.output {
-            if output.interleave {
+            if output.interleave && !self.input.interleaved {
                 bail!("Interleaving requires read2 files to be specified.");
             }
         }
@@ -302,11 +514,20 @@ impl Config {
             }
         }
 
-        if self.options.block_size % 2 == 1 && self.input.interleaved {
+        self.check_bam_input()?;
+
+        if self.options.block_size % 2 == 1
+            && self.input.interleaved
+            && self.input.format != InputFormat::Bam
+        {
             bail!("Block size must be even for interleaved input.");
         }
 
         let mut tags_available: HashMap<String, bool> = HashMap::new();
+        for tag_name in &self.input.bam_import_tags {
+            //imported tags have no location within the read
+            tags_available.insert(tag_name.clone(), false);
+        }
         // check each transformation, validate labels
         for t in &self.transform {
             t.validate(&self.input, self.output.as_ref(), &self.transform)
@@ -362,11 +583,44 @@ impl Config {
             }
         }
 
+        // singletons are not split by barcode
+        if self
+            .transform
+            .iter()
+            .skip_while(|t| !matches!(t, Transformation::Demultiplex(_)))
+            .any(|t| t.emits_orphans())
+        {
+            bail!("paired_end = 'EmitOrphans' can not be used after Demultiplex (singletons are not demultiplexed). Move the filter before the Demultiplex step.");
+        }
+
+        if let Some(output) = &self.output {
+            Self::check_bam_output(output, &tags_available)?;
+            Self::check_fasta_output(output)?;
+            validate_compression_level(output.compression_format(), output.compression_level)?;
+            if output.zstd_long && output.compression_format() != CompressionFormat::Zstd {
+                bail!("zstd_long is only supported for zstd compressed output.");
+            }
+            if output.output_rejected
+                && (output.stdout || matches!(output.format, FileFormat::None))
+            {
+                bail!("output_rejected needs file output. It can not be combined with stdout or format = 'None'.");
+            }
+            if self.transform.iter().any(|t| t.emits_orphans())
+                && (output.stdout || matches!(output.format, FileFormat::Bam))
+            {
+                bail!("paired_end = 'EmitOrphans' writes {{prefix}}_singletons_1/_2 files. It can not be combined with stdout or BAM output.");
+            }
+        }
+
         //apply output if set
         if let Some(output) = &mut self.output {
             if output.stdout {
-                output.format = FileFormat::Raw;
-                output.interleave = self.input.read2.is_some();
+                if matches!(output.format, FileFormat::Fasta) {
+                    output.compression = None;
+                } else {
+                    output.format = FileFormat::Raw;
+                }
+                output.interleave = self.input.has_read2();
             }
         }
 
@@ -374,10 +628,10 @@ impl Config {
         let report_json = self.output.as_ref().is_some_and(|o| o.report_json);
 
         if report_html || report_json {
-            let has_report_transforms = self.transform.iter().any(|t| {
-                matches!(t, Transformation::Report { .. })
-                    | matches!(t, Transformation::_InternalReadCount { .. })
-            });
+            let has_report_transforms = self
+                .transform
+                .iter()
+                .any(|t| matches!(t, Transformation::Report { .. }) || t.report_label().is_some());
             if !has_report_transforms {
                 bail!("Report (html|json) requested, but no report step in configuration. Either disable the reporting, or add a
 \"\"\"
@@ -391,4 +645,109 @@ impl Config {
 
         Ok(())
     }
+
+    fn check_bam_output(output: &Output, tags_available: &HashMap<String, bool>) -> Result<()> {
+        if !matches!(output.format, FileFormat::Bam) {
+            if !output.bam_tags.is_empty() {
+                bail!("output.bam_tags is only supported for format = 'BAM'.");
+            }
+            return Ok(());
+        }
+        if output.stdout {
+            bail!("BAM output can not be written to stdout.");
+        }
+        if output.output_i1 || output.output_i2 {
+            bail!("BAM output does not contain index reads. Set output_i1/output_i2 to false.");
+        }
+        if output.output_hash {
+            bail!("output_hash is not supported for BAM output.");
+        }
+        for (label, aux_tag) in &output.bam_tags {
+            if aux_tag.len() != 2 || !aux_tag.bytes().all(|x| x.is_ascii_alphanumeric()) {
+                bail!("Invalid BAM tag name '{aux_tag}' for tag '{label}' in output.bam_tags. Must be two alphanumeric characters.");
+            }
+            if !tags_available.contains_key(label) {
+                bail!("output.bam_tags refers to tag '{label}', which is not available at the end of the pipeline. Available: {tags_available:?}");
+            }
+            if output.output_rejected && aux_tag.as_bytes() == REJECTED_BY_BAM_TAG {
+                bail!("BAM tag '{aux_tag}' for tag '{label}' in output.bam_tags is used for the rejecting step with output_rejected = true. Choose another one.");
+            }
+        }
+        Ok(())
+    }
+
+    fn check_stdin_input(&self) -> Result<()> {
+        let stdin_count = self
+            .input
+            .read1
+            .iter()
+            .chain(self.input.read2.iter().flatten())
+            .chain(self.input.index1.iter().flatten())
+            .chain(self.input.index2.iter().flatten())
+            .filter(|f| crate::io::is_stdin(f))
+            .count();
+        if stdin_count > 1 {
+            bail!("stdin ('-') can only be used for one input file. Use interleaved = true to read read1 and read2 from stdin.");
+        }
+        Ok(())
+    }
+
+    fn check_fasta_output(output: &Output) -> Result<()> {
+        if !matches!(output.format, FileFormat::Fasta) {
+            if output.compression.is_some() {
+                bail!("output.compression is only used for format = 'FASTA'. For FastQ, set the format to Raw/Gzip/Zstd.");
+            }
+            if output.fasta_line_width.is_some() {
+                bail!("output.fasta_line_width is only supported for format = 'FASTA'.");
+            }
+            return Ok(());
+        }
+        if output.fasta_line_width == Some(0) {
+            bail!("output.fasta_line_width must be > 0. Leave it off to write each sequence on a single line.");
+        }
+        Ok(())
+    }
+
+    fn check_bam_input(&mut self) -> Result<()> {
+        let is_bam_filename = |f: &String| {
+            std::path::Path::new(f)
+                .extension()
+                .is_some_and(|ext| ext == "bam" || ext == "sam")
+        };
+        if self.input.format == InputFormat::Auto {
+            let bam_count = self
+                .input
+                .read1
+                .iter()
+                .filter(|f| is_bam_filename(f))
+                .count();
+            if bam_count == self.input.read1.len() {
+                self.input.format = InputFormat::Bam;
+            } else if bam_count > 0 {
+                bail!("Either all or none of the read1 files must be BAM/SAM files.");
+            }
+        }
+        if self.input.format == InputFormat::Bam {
+            if self.input.read2.is_some()
+                || self.input.index1.is_some()
+                || self.input.index2.is_some()
+            {
+                bail!(
+                    "BAM input is read1 only (set interleaved = true for paired records). read2/index1/index2 must not be set."
+                );
+            }
+        } else if !self.input.bam_import_tags.is_empty() {
+            bail!("bam_import_tags is only supported for BAM/SAM input.");
+        }
+        let mut seen = HashSet::new();
+        for tag_name in &self.input.bam_import_tags {
+            if tag_name.len() != 2 || !tag_name.bytes().all(|x| x.is_ascii_alphanumeric()) {
+                bail!("Invalid BAM tag name '{tag_name}' in bam_import_tags. Must be two alphanumeric characters.");
+            }
+            if !seen.insert(tag_name) {
+                bail!("Repeated BAM tag name '{tag_name}' in bam_import_tags.");
+            }
+        }
+        Ok(())
+    }
 }
@@ -1,30 +1,45 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
 
 use anyhow::{Context, Result, bail};
 
+/// The output tag of rejected reads that were removed before the (last) Demultiplex step,
+/// and so have no final barcode. Never assigned to an output (see `DemultiplexInfo::push`).
+pub const REJECTED_BEFORE_DEMULTIPLEX: u16 = u16::MAX;
+
 /// what the other steps need to know about the demultiplexing
 #[derive(Debug, Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub struct DemultiplexInfo {
-    names: Vec<String>,                    //these include all outputs
-    barcode_to_tag: HashMap<Vec<u8>, u16>, //tag is never 0 in this
-    include_no_barcode: bool,              //only relevant for output
+    names: Vec<String>,   //indexed by tag. Tag 0 is always 'no-barcode'
+    is_output: Vec<bool>, //unmatched reads that are not output get a tag with is_output = false
 }
 
 impl DemultiplexInfo {
-    pub fn new(
+    /// Combine a previous demultiplex level (if any) with a new one.
+    ///
+    /// Returns the info for the combined outputs, and the tag lookup
+    /// `[previous tag][barcode index]` -> combined tag,
+    /// where barcode index 0 means 'no barcode matched',
+    /// and 1.. follows the order of `barcode_to_name`.
+    /// If `include_ambiguous`, the index after the last barcode
+    /// means 'matched more than one barcode'.
+    ///
+    /// Reads that matched no barcode on the first level stay in the 'no-barcode' output.
+    /// On later levels, they go to '{previous}_no-barcode', and are dropped if
+    /// `include_no_barcode` is false. Dropped reads stay dropped.
+    pub fn new_level(
+        previous: Option<&DemultiplexInfo>,
         barcode_to_name: &BTreeMap<Vec<u8>, String>,
         include_no_barcode: bool,
-    ) -> Result<Self> {
-        let mut names = Vec::new();
-        let mut barcode_to_tag = HashMap::new();
-        if include_no_barcode {
-            names.push("no-barcode".to_string());
-        }
-        for (tag, (barcode, name)) in barcode_to_name.iter().enumerate() {
+        include_ambiguous: bool,
+    ) -> Result<(Self, Vec<Vec<u16>>)> {
+        for name in barcode_to_name.values() {
             if name == "no-barcode" {
                 bail!("Barcode output infix must not be 'no-barcode'");
             }
+            if name == "ambiguous" {
+                bail!("Barcode output infix must not be 'ambiguous'");
+            }
             // no longer true. We combine outputs from multiple barcodes if
             // the user wishes
             /* if names.contains(name) {
@@ -33,49 +48,85 @@ impl DemultiplexInfo {
                     name
                 )
             } */
-            names.push(name.clone());
-            let tag = tag + 1;
-            barcode_to_tag.insert(
-                barcode.clone(),
-                tag.try_into().context("too many barcodes")?,
-            );
         }
-        Ok(Self {
-            names,
-            barcode_to_tag,
-            include_no_barcode,
-        })
-    }
-
-    #[must_use]
-    pub fn barcode_to_tag(&self, barcode: &[u8]) -> Option<u16> {
-        self.barcode_to_tag.get(barcode).copied()
+        let mut res = Self {
+            names: vec!["no-barcode".to_string()],
+            is_output: vec![],
+        };
+        let mut tag_lookup = Vec::new();
+        match previous {
+            None => {
+                res.is_output.push(include_no_barcode);
+                let mut row = vec![0];
+                for name in barcode_to_name.values() {
+                    row.push(res.push(name.clone(), true)?);
+                }
+                if include_ambiguous {
+                    row.push(res.push("ambiguous".to_string(), true)?);
+                }
+                tag_lookup.push(row);
+            }
+            Some(previous) => {
+                res.is_output.push(previous.is_output[0]);
+                let dropped = if previous.is_output[0] {
+                    res.push("dropped".to_string(), false)?
+                } else {
+                    0
+                };
+                let level_count = barcode_to_name.len() + 1 + usize::from(include_ambiguous);
+                tag_lookup.push(vec![0; level_count]);
+                for (prev_name, prev_is_output) in
+                    previous.names.iter().zip(previous.is_output.iter()).skip(1)
+                {
+                    if !prev_is_output {
+                        tag_lookup.push(vec![dropped; level_count]);
+                        continue;
+                    }
+                    let mut row = vec![if include_no_barcode {
+                        res.push(format!("{prev_name}_no-barcode"), true)?
+                    } else {
+                        dropped
+                    }];
+                    for name in barcode_to_name.values() {
+                        row.push(res.push(format!("{prev_name}_{name}"), true)?);
+                    }
+                    if include_ambiguous {
+                        row.push(res.push(format!("{prev_name}_ambiguous"), true)?);
+                    }
+                    tag_lookup.push(row);
+                }
+            }
+        }
+        Ok((res, tag_lookup))
     }
 
-    /// Iterate (barcode, tag) tuples
-    /// this never includes the no-barcode output
-    pub fn iter_barcodes(&self) -> impl Iterator<Item = (&Vec<u8>, u16)> {
-        //self.barcode_to_tag.iter()
-        self.barcode_to_tag
-            .iter()
-            .map(|(barcode, tag)| (barcode, *tag))
+    fn push(&mut self, name: String, is_output: bool) -> Result<u16> {
+        let tag = u16::try_from(self.names.len())
+            .ok()
+            .filter(|tag| *tag < u16::MAX)
+            .context(
+                "Too many barcode combinations. Can demultiplex into at most 2^16-1 outputs",
+            )?;
+        self.names.push(name);
+        self.is_output.push(is_output);
+        Ok(tag)
     }
 
     /// Iterate `(tag, output_name)` tuples.
     /// this includes the no-barcode output if it exists
     #[allow(clippy::cast_possible_truncation)]
     pub fn iter_outputs(&self) -> impl Iterator<Item = (u16, &str)> {
-        self.names.iter().enumerate().map(|(tag, name)| {
-            (
-                (tag + usize::from(!self.include_no_barcode)) as u16,
-                name.as_str(),
-            )
-        })
+        self.names
+            .iter()
+            .zip(self.is_output.iter())
+            .enumerate()
+            .filter(|(_tag, (_name, is_output))| **is_output)
+            .map(|(tag, (name, _is_output))| (tag as u16, name.as_str()))
     }
 
     #[must_use]
     pub fn len_outputs(&self) -> usize {
-        self.names.len()
+        self.is_output.iter().filter(|x| **x).count()
     }
 }
 
@@ -86,18 +137,12 @@ pub enum Demultiplexed {
 }
 
 impl Demultiplexed {
-    #[allow(clippy::cast_possible_truncation)]
     pub fn iter_tags(&self) -> impl Iterator<Item = u16> {
-        match self {
-            Self::No => 0..1,
-            Self::Yes(info) => {
-                if info.include_no_barcode {
-                    0..info.names.len() as u16
-                } else {
-                    1..info.names.len() as u16
-                }
-            }
-        }
+        let tags: Vec<u16> = match self {
+            Self::No => vec![0],
+            Self::Yes(info) => info.iter_outputs().map(|(tag, _name)| tag).collect(),
+        };
+        tags.into_iter()
     }
 
     #[must_use]
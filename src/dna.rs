@@ -1,4 +1,5 @@
 use crate::config::Target;
+use std::collections::{HashMap, HashSet};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct HitRegion {
@@ -128,6 +129,25 @@ pub fn find_iupac(
     None
 }
 
+/// Like `find_iupac`, but tolerating insertions and deletions
+/// (at most `max_error_rate` errors per query base, see `align_iupac`).
+pub fn find_iupac_with_indels(
+    reference: &[u8],
+    query: &[u8],
+    anchor: Anchor,
+    max_error_rate: f32,
+    target: Target,
+) -> Option<Hits> {
+    align_iupac(query, reference, anchor, max_error_rate).map(|hit| {
+        Hits::new(
+            hit.start,
+            hit.end - hit.start,
+            target,
+            reference[hit.start..hit.end].to_vec(),
+        )
+    })
+}
+
 ///find the best hit for this iupac string, on parity, earlier hits prefered
 pub fn iupac_find_best(query: &[u8], reference: &[u8], max_mismatches: usize) -> Option<usize> {
     let query_len = query.len();
@@ -179,45 +199,46 @@ pub fn all_iupac(input: &[u8]) -> bool {
 }
 
 pub fn reverse_complement_iupac(input: &[u8]) -> Vec<u8> {
-    let mut new_seq = Vec::new();
-    for char in input.iter().rev() {
-        new_seq.push(match char {
-            b'A' => b'T',
-            b'T' | b'U' => b'A',
-            b'C' => b'G',
-            b'G' => b'C',
-
-            b'a' => b't',
-            b't' | b'u' => b'a',
-            b'c' => b'g',
-            b'g' => b'c',
-
-            b'R' => b'Y',
-            b'Y' => b'R',
-            b'S' => b'S',
-            b'W' => b'W',
-            b'K' => b'M',
-            b'M' => b'K',
-            b'B' => b'V',
-            b'V' => b'B',
-            b'D' => b'H',
-            b'H' => b'D',
-
-            b'r' => b'y',
-            b'y' => b'r',
-            b's' => b's',
-            b'w' => b'w',
-            b'k' => b'm',
-            b'm' => b'k',
-            b'b' => b'v',
-            b'v' => b'b',
-            b'd' => b'h',
-            b'h' => b'd',
-            b'\n' => panic!("New line in DNA sequence"), // since that's not valid fastq!
-            _ => *char,
-        });
+    input.iter().rev().copied().map(complement_iupac).collect()
+}
+
+#[must_use]
+pub fn complement_iupac(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'T' | b'U' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+
+        b'a' => b't',
+        b't' | b'u' => b'a',
+        b'c' => b'g',
+        b'g' => b'c',
+
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'S' => b'S',
+        b'W' => b'W',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+
+        b'r' => b'y',
+        b'y' => b'r',
+        b's' => b's',
+        b'w' => b'w',
+        b'k' => b'm',
+        b'm' => b'k',
+        b'b' => b'v',
+        b'v' => b'b',
+        b'd' => b'h',
+        b'h' => b'd',
+        b'\n' => panic!("New line in DNA sequence"), // since that's not valid fastq!
+        _ => base,
     }
-    new_seq
 }
 
 pub fn iupac_hamming_distance(iupac_reference: &[u8], atcg_query: &[u8]) -> usize {
@@ -226,11 +247,20 @@ pub fn iupac_hamming_distance(iupac_reference: &[u8], atcg_query: &[u8]) -> usiz
         atcg_query.len(),
         "Reference and query must have same length"
     );
-    let mut dist = 0;
-    for (a, b) in iupac_reference.iter().zip(atcg_query.iter()) {
-        if a != b {
-            match (a, b) {
-                (b'A', b'a')
+    iupac_reference
+        .iter()
+        .zip(atcg_query.iter())
+        .filter(|(a, b)| !iupac_matches(**a, **b))
+        .count()
+}
+
+/// Does the (atcg) base match the iupac code (case insensitive)?
+#[must_use]
+pub fn iupac_matches(iupac: u8, base: u8) -> bool {
+    iupac == base
+        || matches!(
+            (iupac, base),
+            (b'A', b'a')
                 | (b'a', b'A')
                 | (b'C', b'c')
                 | (b'c', b'C')
@@ -248,12 +278,553 @@ pub fn iupac_hamming_distance(iupac_reference: &[u8], atcg_query: &[u8]) -> usiz
                 | (b'D' | b'd', b'A' | b'G' | b'T' | b'a' | b'g' | b't')
                 | (b'H' | b'h', b'A' | b'C' | b'T' | b'a' | b'c' | b't')
                 | (b'V' | b'v', b'A' | b'C' | b'G' | b'a' | b'c' | b'g')
-                | (b'N' | b'n', _) => {}
-                (_, _) => dist += 1,
+                | (b'N' | b'n', _)
+        )
+}
+
+/// Could the two (IUPAC) codes stand for the same base?
+#[must_use]
+pub fn iupac_compatible(a: u8, b: u8) -> bool {
+    b"ACGT"
+        .iter()
+        .any(|base| iupac_matches(a, *base) && iupac_matches(b, *base))
+}
+
+/// A hit of an (IUPAC) query in a reference, found by semi-global alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignmentHit {
+    /// start in the reference
+    pub start: usize,
+    /// end in the reference (exclusive)
+    pub end: usize,
+    /// number of query bases aligned.
+    /// Less than the query length for partial hits at the end of the reference
+    pub query_len: usize,
+    /// substitutions + insertions + deletions
+    pub errors: usize,
+}
+
+/// Edit distance dynamic programming, query along the rows, reference along the columns.
+/// The query must be aligned completely, leading reference bases are free if `free_start`.
+///
+/// Returns (errors, start in reference) for the full query ending at each reference position
+/// (the last row), and for each query prefix ending at the end of the reference (the last column).
+#[allow(clippy::type_complexity)]
+fn semi_global_alignment(
+    query: &[u8],
+    reference: &[u8],
+    free_start: bool,
+) -> (Vec<(usize, usize)>, Vec<(usize, usize)>) {
+    let query_len = query.len();
+    let mut column: Vec<(usize, usize)> = (0..=query_len).map(|ii| (ii, 0)).collect();
+    let mut last_row = Vec::with_capacity(reference.len() + 1);
+    last_row.push(column[query_len]);
+    for (jj, &base) in reference.iter().enumerate() {
+        let mut diagonal = column[0];
+        column[0] = if free_start { (0, jj + 1) } else { (jj + 1, 0) };
+        for ii in 1..=query_len {
+            let substitution = (
+                diagonal.0 + usize::from(!iupac_matches(query[ii - 1], base)),
+                diagonal.1,
+            );
+            let deletion = (column[ii - 1].0 + 1, column[ii - 1].1);
+            let insertion = (column[ii].0 + 1, column[ii].1);
+            diagonal = column[ii];
+            // on parity, prefer the substitution / match
+            column[ii] = [substitution, deletion, insertion]
+                .into_iter()
+                .min_by_key(|(errors, _start)| *errors)
+                .expect("three candidates");
+        }
+        last_row.push(column[query_len]);
+    }
+    (last_row, column)
+}
+
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn max_errors(aligned_len: usize, max_error_rate: f32) -> usize {
+    (aligned_len as f32 * max_error_rate) as usize
+}
+
+/// Find an IUPAC query in a reference, allowing substitutions and indels
+/// (cutadapt semantics: at most `max_error_rate` * query length errors).
+///
+/// The hit with the fewest errors wins, on parity the earlier one.
+#[must_use]
+pub fn align_iupac(
+    query: &[u8],
+    reference: &[u8],
+    anchor: Anchor,
+    max_error_rate: f32,
+) -> Option<AlignmentHit> {
+    if query.is_empty() {
+        return None;
+    }
+    let (last_row, _) = semi_global_alignment(query, reference, !matches!(anchor, Anchor::Left));
+    let limit = max_errors(query.len(), max_error_rate);
+    let candidates = match anchor {
+        Anchor::Right => reference.len()..reference.len() + 1,
+        Anchor::Left | Anchor::Anywhere => 0..reference.len() + 1,
+    };
+    candidates
+        .map(|end| {
+            let (errors, start) = last_row[end];
+            AlignmentHit {
+                start,
+                end,
+                query_len: query.len(),
+                errors,
+            }
+        })
+        .filter(|hit| hit.errors <= limit && hit.end > hit.start)
+        .min_by_key(|hit| (hit.errors, hit.start))
+}
+
+/// Find a 3' adapter in a read, allowing substitutions and indels (cutadapt semantics).
+///
+/// The adapter either occurs completely, or at least `min_length` bases of its start
+/// run into the end of the read. Each hit may have at most `max_error_rate` errors
+/// per aligned adapter base. The earliest hit wins (so the most is trimmed).
+#[must_use]
+pub fn align_adapter_3prime(
+    query: &[u8],
+    reference: &[u8],
+    min_length: usize,
+    max_error_rate: f32,
+) -> Option<AlignmentHit> {
+    if query.is_empty() {
+        return None;
+    }
+    let (last_row, last_column) = semi_global_alignment(query, reference, true);
+    let full_hits = last_row
+        .iter()
+        .enumerate()
+        .map(|(end, (errors, start))| AlignmentHit {
+            start: *start,
+            end,
+            query_len: query.len(),
+            errors: *errors,
+        });
+    let partial_hits = last_column
+        .iter()
+        .enumerate()
+        .skip(min_length.max(1))
+        .take(query.len().saturating_sub(min_length.max(1)))
+        .map(|(query_len, (errors, start))| AlignmentHit {
+            start: *start,
+            end: reference.len(),
+            query_len,
+            errors: *errors,
+        });
+    full_hits
+        .chain(partial_hits)
+        .filter(|hit| {
+            hit.errors <= max_errors(hit.query_len, max_error_rate) && hit.end > hit.start
+        })
+        .min_by_key(|hit| (hit.start, hit.errors))
+}
+
+/// Find a 5' adapter in a read - the mirror image of `align_adapter_3prime`:
+/// The adapter either occurs completely, or at least `min_length` bases of its end
+/// are at the start of the read. The latest hit wins (so the most is trimmed).
+#[must_use]
+pub fn align_adapter_5prime(
+    query: &[u8],
+    reference: &[u8],
+    min_length: usize,
+    max_error_rate: f32,
+) -> Option<AlignmentHit> {
+    let query_rev: Vec<u8> = query.iter().rev().copied().collect();
+    let reference_rev: Vec<u8> = reference.iter().rev().copied().collect();
+    align_adapter_3prime(&query_rev, &reference_rev, min_length, max_error_rate).map(|hit| {
+        AlignmentHit {
+            start: reference.len() - hit.end,
+            end: reference.len() - hit.start,
+            ..hit
+        }
+    })
+}
+
+/// How read1 and the reverse complement of read2 line up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairOverlap {
+    /// Start of the reverse complemented read2 in read1 coordinates.
+    /// Negative if the reads ran through the insert into the adapters.
+    pub offset: isize,
+    /// Number of overlapping bases
+    pub len: usize,
+    pub mismatches: usize,
+}
+
+impl PairOverlap {
+    /// Length of the sequenced fragment (without adapters)
+    #[must_use]
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    pub fn insert_size(&self, read2_len: usize) -> usize {
+        (self.offset + read2_len as isize) as usize
+    }
+
+    /// First overlapping position in read1
+    #[must_use]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn read1_start(&self) -> usize {
+        self.offset.max(0) as usize
+    }
+
+    /// First overlapping position in the reverse complemented read2
+    #[must_use]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn read2_rc_start(&self) -> usize {
+        (-self.offset).max(0) as usize
+    }
+}
+
+/// Find the overlap of read1 and the reverse complement of read2 (fastp style).
+///
+/// Tries offsets >= 0 (inserts longer than read1) first, then negative ones
+/// (adapter read through), and returns the first one
+/// with at least `min_overlap` bases and at most
+/// min(`max_mismatches`, overlap * `max_mismatch_rate`) mismatches.
+#[must_use]
+#[allow(
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss
+)]
+pub fn find_pair_overlap(
+    read1: &[u8],
+    read2_rc: &[u8],
+    min_overlap: usize,
+    max_mismatches: usize,
+    max_mismatch_rate: f32,
+) -> Option<PairOverlap> {
+    let check = |offset: isize| -> Option<PairOverlap> {
+        let read1_start = offset.max(0) as usize;
+        let read2_start = (-offset).max(0) as usize;
+        let len = (read1.len() - read1_start).min(read2_rc.len() - read2_start);
+        if len < min_overlap || len == 0 {
+            return None;
+        }
+        let limit = max_mismatches.min((len as f32 * max_mismatch_rate) as usize);
+        let mut mismatches = 0;
+        for (a, b) in read1[read1_start..read1_start + len]
+            .iter()
+            .zip(&read2_rc[read2_start..read2_start + len])
+        {
+            if a != b {
+                mismatches += 1;
+                if mismatches > limit {
+                    return None;
+                }
+            }
+        }
+        Some(PairOverlap {
+            offset,
+            len,
+            mismatches,
+        })
+    };
+    (0..=read1.len().saturating_sub(min_overlap))
+        .map(|offset| offset as isize)
+        .chain((1..=read2_rc.len().saturating_sub(min_overlap)).map(|offset| -(offset as isize)))
+        .find_map(check)
+}
+
+const PHRED_OFFSET: u8 = 33;
+
+/// Base and quality where the two mates cover the same position:
+/// agreeing bases keep the higher quality, on disagreement the higher quality base
+/// wins, with the difference of the qualities as its new quality.
+#[must_use]
+pub fn consensus_base(base1: u8, qual1: u8, base2: u8, qual2: u8) -> (u8, u8) {
+    if base1 == base2 {
+        (base1, qual1.max(qual2))
+    } else if qual1 >= qual2 {
+        (base1, PHRED_OFFSET + (qual1 - qual2))
+    } else {
+        (base2, PHRED_OFFSET + (qual2 - qual1))
+    }
+}
+
+/// Merge read1 and the reverse complement of read2 into the
+/// sequence (and qualities) of the insert they overlap on.
+/// `qual2_rev` are read2's qualities, reversed.
+#[must_use]
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+pub fn merge_pair(
+    read1: &[u8],
+    qual1: &[u8],
+    read2_rc: &[u8],
+    qual2_rev: &[u8],
+    overlap: &PairOverlap,
+) -> (Vec<u8>, Vec<u8>) {
+    let insert_size = overlap.insert_size(read2_rc.len());
+    let mut seq = Vec::with_capacity(insert_size);
+    let mut qual = Vec::with_capacity(insert_size);
+    for pos in 0..insert_size {
+        // position in read2_rc, always < read2_rc.len()
+        let pos2 = pos as isize - overlap.offset;
+        let (base, base_qual) = if pos2 < 0 {
+            (read1[pos], qual1[pos])
+        } else if pos >= read1.len() {
+            (read2_rc[pos2 as usize], qual2_rev[pos2 as usize])
+        } else {
+            consensus_base(
+                read1[pos],
+                qual1[pos],
+                read2_rc[pos2 as usize],
+                qual2_rev[pos2 as usize],
+            )
+        };
+        seq.push(base);
+        qual.push(base_qual);
+    }
+    (seq, qual)
+}
+
+/// Correct mismatches within the overlap of a read pair in place.
+///
+/// The base with the higher quality wins and is written into both mates
+/// (complemented for read2), the corrected base receives the winner's quality.
+/// Mismatches with equal qualities are left alone.
+/// Returns the number of bases corrected in read1 and read2.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+pub fn correct_pair_overlap(
+    seq1: &mut [u8],
+    qual1: &mut [u8],
+    seq2: &mut [u8],
+    qual2: &mut [u8],
+    overlap: &PairOverlap,
+) -> (usize, usize) {
+    let mut corrected = (0, 0);
+    for ii in 0..overlap.len {
+        let pos1 = overlap.read1_start() + ii;
+        // position in read2's original orientation
+        let pos2 = seq2.len() - 1 - (overlap.read2_rc_start() + ii);
+        let base2 = complement_iupac(seq2[pos2]);
+        if seq1[pos1] == base2 {
+            continue;
+        }
+        match qual1[pos1].cmp(&qual2[pos2]) {
+            std::cmp::Ordering::Greater => {
+                seq2[pos2] = complement_iupac(seq1[pos1]);
+                qual2[pos2] = qual1[pos1];
+                corrected.1 += 1;
+            }
+            std::cmp::Ordering::Less => {
+                seq1[pos1] = base2;
+                qual1[pos1] = qual2[pos2];
+                corrected.0 += 1;
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+    corrected
+}
+
+/// k-mer length used to seed the adapter detection
+const ADAPTER_SEED_LENGTH: usize = 12;
+/// fraction of reads that must agree on a base to extend the adapter seed
+const ADAPTER_MIN_AGREEMENT: f64 = 0.8;
+/// adapters are not extended beyond this length
+const ADAPTER_MAX_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedAdapter {
+    pub sequence: Vec<u8>,
+    /// number of reads containing the seed k-mer
+    pub supporting_reads: usize,
+}
+
+/// Detect an adapter from over-represented sequences in a sample of reads.
+///
+/// The most common k-mer (counted once per read, ignoring low complexity ones)
+/// seeds the adapter if at least `min_count` reads contain it.
+/// Like fastp, only k-mers found at varying positions may seed:
+/// adapters follow inserts of varying length, while an insert
+/// duplicated at a fixed position (e.g. an amplicon) is not an adapter.
+/// It is then extended base by base in both directions (up to 64 bases)
+/// for as long as the reads containing it agree on the next base.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn detect_adapter(reads: &[Vec<u8>], min_count: usize) -> Option<DetectedAdapter> {
+    struct SeedCount {
+        count: usize,
+        first_position: usize,
+        position_varies: bool,
+    }
+    let min_count = min_count.max(1);
+    let mut counts: HashMap<&[u8], SeedCount> = HashMap::new();
+    let mut seen = HashSet::new();
+    for read in reads {
+        seen.clear();
+        for (pos, kmer) in read.windows(ADAPTER_SEED_LENGTH).enumerate() {
+            if seen.insert(kmer) && is_adapter_seed_candidate(kmer) {
+                let entry = counts.entry(kmer).or_insert(SeedCount {
+                    count: 0,
+                    first_position: pos,
+                    position_varies: false,
+                });
+                entry.count += 1;
+                entry.position_varies |= entry.first_position != pos;
             }
         }
     }
-    dist
+    // ties are broken by the sequence to stay deterministic
+    let (seed, supporting_reads) = counts
+        .into_iter()
+        .filter(|(_, seed_count)| seed_count.position_varies)
+        .map(|(kmer, seed_count)| (kmer, seed_count.count))
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))?;
+    if supporting_reads < min_count {
+        return None;
+    }
+    let seed_positions: Vec<(&[u8], usize)> = reads
+        .iter()
+        .filter_map(|read| {
+            read.windows(ADAPTER_SEED_LENGTH)
+                .position(|kmer| kmer == seed)
+                .map(|pos| (read.as_slice(), pos))
+        })
+        .collect();
+    let consensus = |base_at: &dyn Fn(&[u8], usize) -> Option<u8>| -> Option<u8> {
+        let mut base_counts = [0usize; 256];
+        let mut total = 0;
+        for (read, pos) in &seed_positions {
+            if let Some(base) = base_at(read, *pos) {
+                base_counts[base as usize] += 1;
+                total += 1;
+            }
+        }
+        let (base, count) = base_counts
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, count)| **count)
+            .expect("256 entries");
+        (total >= min_count && *count as f64 >= total as f64 * ADAPTER_MIN_AGREEMENT)
+            .then(|| u8::try_from(base).expect("base index < 256"))
+    };
+
+    let mut sequence = seed.to_vec();
+    for offset in 1.. {
+        if sequence.len() >= ADAPTER_MAX_LENGTH {
+            break;
+        }
+        match consensus(&|read: &[u8], pos| (pos >= offset).then(|| read[pos - offset])) {
+            Some(base) => sequence.insert(0, base),
+            None => break,
+        }
+    }
+    for offset in ADAPTER_SEED_LENGTH.. {
+        if sequence.len() >= ADAPTER_MAX_LENGTH {
+            break;
+        }
+        match consensus(&|read: &[u8], pos| read.get(pos + offset).copied()) {
+            Some(base) => sequence.push(base),
+            None => break,
+        }
+    }
+    Some(DetectedAdapter {
+        sequence,
+        supporting_reads,
+    })
+}
+
+/// Detect an adapter from the bases beyond the insert end of paired reads
+/// (see `find_pair_overlap`), one `Vec` per pair that ran into the adapter.
+///
+/// Needs at least `min_count` of them. The adapter is their consensus,
+/// extended base by base (up to 64 bases) for as long as at least `min_count`
+/// of them cover the position and 80% of those agree on the base.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn detect_adapter_from_read_through(
+    adapter_ends: &[Vec<u8>],
+    min_count: usize,
+) -> Option<DetectedAdapter> {
+    let min_count = min_count.max(1);
+    if adapter_ends.len() < min_count {
+        return None;
+    }
+    let mut sequence = Vec::new();
+    while sequence.len() < ADAPTER_MAX_LENGTH {
+        let mut base_counts = [0usize; 256];
+        let mut total = 0;
+        for adapter_end in adapter_ends {
+            if let Some(base) = adapter_end.get(sequence.len()) {
+                base_counts[*base as usize] += 1;
+                total += 1;
+            }
+        }
+        let (base, count) = base_counts
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, count)| **count)
+            .expect("256 entries");
+        if total < min_count || (*count as f64) < total as f64 * ADAPTER_MIN_AGREEMENT {
+            break;
+        }
+        sequence.push(u8::try_from(base).expect("base index < 256"));
+    }
+    (!sequence.is_empty()).then_some(DetectedAdapter {
+        sequence,
+        supporting_reads: adapter_ends.len(),
+    })
+}
+
+/// Only ACGT k-mers with at least three different bases
+/// (no poly-X or dinucleotide repeats) may seed an adapter.
+fn is_adapter_seed_candidate(kmer: &[u8]) -> bool {
+    let mut present = [false; 4];
+    for base in kmer {
+        match base {
+            b'A' => present[0] = true,
+            b'C' => present[1] = true,
+            b'G' => present[2] = true,
+            b'T' => present[3] = true,
+            _ => return false,
+        }
+    }
+    present.iter().filter(|x| **x).count() >= 3
+}
+
+/// Call `f` with the canonical 2-bit encoding (the smaller one of the k-mer
+/// and its reverse complement) of every k-mer in `seq`.
+/// K-mers containing anything but ACGT (case insensitive) are skipped.
+pub fn for_each_canonical_kmer(seq: &[u8], k: usize, mut f: impl FnMut(u64)) {
+    assert!((1..=32).contains(&k), "k must be in 1..=32");
+    let mask = if k == 32 {
+        u64::MAX
+    } else {
+        (1u64 << (2 * k)) - 1
+    };
+    let shift = 2 * (k - 1);
+    let mut forward = 0u64;
+    let mut reverse = 0u64;
+    let mut valid = 0;
+    for base in seq {
+        let code: u64 = match base {
+            b'A' | b'a' => 0,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' => 3,
+            _ => {
+                valid = 0;
+                continue;
+            }
+        };
+        forward = ((forward << 2) | code) & mask;
+        reverse = (reverse >> 2) | ((3 - code) << shift);
+        valid += 1;
+        if valid >= k {
+            f(forward.min(reverse));
+        }
+    }
 }
 
 #[cfg(test)]
@@ -352,6 +923,15 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_iupac_compatible() {
+        assert!(super::iupac_compatible(b'A', b'A'));
+        assert!(!super::iupac_compatible(b'A', b'C'));
+        assert!(super::iupac_compatible(b'N', b'C'));
+        assert!(super::iupac_compatible(b'R', b'K')); // both may be G
+        assert!(!super::iupac_compatible(b'R', b'Y'));
+    }
+
     #[test]
     fn test_find_iupac() {
         assert_eq!(
@@ -401,4 +981,304 @@ mod test {
             Some(super::Hits::new(0, 2, Target::Index1, b"AG".to_vec(),))
         );
     }
+
+    #[test]
+    fn test_find_pair_overlap() {
+        use super::{find_pair_overlap, reverse_complement_iupac, PairOverlap};
+        let fragment = b"ACGTTGCAAGGCTTACCGATAGCTAGGCTAAC";
+        // insert longer than the reads
+        let read1 = &fragment[..20];
+        let read2 = reverse_complement_iupac(&fragment[fragment.len() - 20..]);
+        let overlap =
+            find_pair_overlap(read1, &reverse_complement_iupac(&read2), 5, 2, 0.2).unwrap();
+        assert_eq!(
+            overlap,
+            PairOverlap {
+                offset: 12,
+                len: 8,
+                mismatches: 0
+            }
+        );
+        assert_eq!(overlap.insert_size(read2.len()), fragment.len());
+        assert_eq!(overlap.read1_start(), 12);
+        assert_eq!(overlap.read2_rc_start(), 0);
+
+        // adapter read through
+        let mut read1 = fragment[..24].to_vec();
+        read1.extend_from_slice(b"AGATCGGAAG");
+        let mut read2 = reverse_complement_iupac(&fragment[..24]);
+        read2.extend_from_slice(b"AGATCGGAAGAG");
+        let overlap =
+            find_pair_overlap(&read1, &reverse_complement_iupac(&read2), 10, 2, 0.2).unwrap();
+        assert_eq!(overlap.offset, -12);
+        assert_eq!(overlap.len, 24);
+        assert_eq!(overlap.insert_size(read2.len()), 24);
+        assert_eq!(overlap.read1_start(), 0);
+        assert_eq!(overlap.read2_rc_start(), 12);
+
+        // a mismatch is tolerated, but not too many
+        let mut read1_mismatch = read1.clone();
+        read1_mismatch[3] = b'A';
+        let overlap = find_pair_overlap(
+            &read1_mismatch,
+            &reverse_complement_iupac(&read2),
+            10,
+            1,
+            0.2,
+        )
+        .unwrap();
+        assert_eq!(overlap.mismatches, 1);
+        assert_eq!(overlap.insert_size(read2.len()), 24);
+        assert!(find_pair_overlap(
+            &read1_mismatch,
+            &reverse_complement_iupac(&read2),
+            10,
+            0,
+            0.2
+        )
+        .is_none());
+
+        // no overlap
+        assert!(find_pair_overlap(b"AAAAAAAAAA", b"CCCCCCCCCC", 5, 1, 0.2).is_none());
+        assert!(find_pair_overlap(b"", b"", 0, 1, 0.2).is_none());
+    }
+
+    #[test]
+    fn test_merge_pair() {
+        use super::{find_pair_overlap, merge_pair};
+        // insert of 12, reads of 8, overlap of 4
+        let read1 = b"ACGTACGA";
+        let qual1 = b"IIIIII5I";
+        let read2_rc = b"ACTATTGC";
+        let qual2_rev = b"II#IIIII";
+        let overlap = find_pair_overlap(read1, read2_rc, 4, 1, 0.5).unwrap();
+        assert_eq!(overlap.offset, 4);
+        assert_eq!(overlap.mismatches, 1);
+        let (seq, qual) = merge_pair(read1, qual1, read2_rc, qual2_rev, &overlap);
+        // position 6: G(5) vs T(#) -> G with quality 5 - # + 33
+        assert_eq!(seq, b"ACGTACGATTGC");
+        assert_eq!(qual, b"IIIIII3IIIII");
+
+        // adapter read through - the insert is only 6 bp
+        let read1 = b"ACGTAAGATC";
+        let read2_rc = b"TCGAACGTAA";
+        let qual = b"IIIIIIIIII";
+        let overlap = find_pair_overlap(read1, read2_rc, 6, 0, 0.0).unwrap();
+        assert_eq!(overlap.insert_size(read2_rc.len()), 6);
+        let (seq, merged_qual) = merge_pair(read1, qual, read2_rc, qual, &overlap);
+        assert_eq!(seq, b"ACGTAA");
+        assert_eq!(merged_qual, b"IIIIII");
+    }
+
+    #[test]
+    fn test_correct_pair_overlap() {
+        use super::{correct_pair_overlap, find_pair_overlap, reverse_complement_iupac};
+        // same pair as in test_merge_pair, read2 in its original orientation
+        let read1 = b"ACGTACGA";
+        let read2 = b"GCAATAGT";
+        let overlap =
+            find_pair_overlap(read1, &reverse_complement_iupac(read2), 4, 1, 0.5).unwrap();
+
+        let (mut seq1, mut qual1) = (read1.to_vec(), b"IIIIII5I".to_vec());
+        let (mut seq2, mut qual2) = (read2.to_vec(), b"IIIII#II".to_vec());
+        let corrected =
+            correct_pair_overlap(&mut seq1, &mut qual1, &mut seq2, &mut qual2, &overlap);
+        assert_eq!(corrected, (0, 1));
+        assert_eq!(seq1, read1);
+        assert_eq!(qual1, b"IIIIII5I");
+        assert_eq!(seq2, b"GCAATCGT");
+        assert_eq!(qual2, b"IIIII5II");
+
+        let (mut seq1, mut qual1) = (read1.to_vec(), b"IIIIII#I".to_vec());
+        let (mut seq2, mut qual2) = (read2.to_vec(), b"IIIII5II".to_vec());
+        let corrected =
+            correct_pair_overlap(&mut seq1, &mut qual1, &mut seq2, &mut qual2, &overlap);
+        assert_eq!(corrected, (1, 0));
+        assert_eq!(seq1, b"ACGTACTA");
+        assert_eq!(qual1, b"IIIIII5I");
+        assert_eq!(seq2, read2);
+
+        // equal qualities - undecidable, left alone
+        let (mut seq1, mut qual1) = (read1.to_vec(), b"IIIIIIII".to_vec());
+        let (mut seq2, mut qual2) = (read2.to_vec(), b"IIIIIIII".to_vec());
+        let corrected =
+            correct_pair_overlap(&mut seq1, &mut qual1, &mut seq2, &mut qual2, &overlap);
+        assert_eq!(corrected, (0, 0));
+        assert_eq!(seq1, read1);
+        assert_eq!(seq2, read2);
+    }
+
+    #[test]
+    fn test_detect_adapter() {
+        use super::detect_adapter;
+        // TruSeq adapter after inserts of 5..12 bp, plus three reads without adapter
+        let reads: Vec<Vec<u8>> = [
+            "GGATCAGATCGGAAGAGCACACGTCTGAAC",
+            "ACAGTCTAAGATCGGAAGAGCACACGTCTG",
+            "CACTGCTCACAGATCGGAAGAGCACACGTC",
+            "TCCAACCCCGGCAGATCGGAAGAGCACACG",
+            "CCCTGAAGATCGGAAGAGCACACGTCTGAA",
+            "GTCCGAGGAAGATCGGAAGAGCACACGTCT",
+            "GAGGGTGCTTCAGAGTATGTATACCACTGG",
+            "GTAGGATACGGCGGAGGGCACGTCAATACG",
+            "GTTCAATGCCCTACTGCATGCTCTTGTGGT",
+        ]
+        .iter()
+        .map(|x| x.as_bytes().to_vec())
+        .collect();
+        let detected = detect_adapter(&reads, 3).unwrap();
+        // extended as long as at least three reads cover the position
+        assert_eq!(
+            std::str::from_utf8(&detected.sequence).unwrap(),
+            "AGATCGGAAGAGCACACGTCTG"
+        );
+        assert_eq!(detected.supporting_reads, 6);
+        assert!(detect_adapter(&reads, 7).is_none());
+        assert!(detect_adapter(&[b"AAAAAAAAAAAAAAAAAAAA".to_vec(); 10], 1).is_none());
+    }
+
+    #[test]
+    fn test_detect_adapter_from_read_through() {
+        use super::detect_adapter_from_read_through;
+        let adapter_ends: Vec<Vec<u8>> = [
+            "AGATCGGAAG",
+            "AGATCGGA",
+            "AGTTCGGAAGAG",
+            "AGATCGG",
+            "AGATC",
+            "A",
+        ]
+        .iter()
+        .map(|x| x.as_bytes().to_vec())
+        .collect();
+        let detected = detect_adapter_from_read_through(&adapter_ends, 3).unwrap();
+        // the mismatch in the third one is outvoted,
+        // and we stop once fewer than three cover the position
+        assert_eq!(std::str::from_utf8(&detected.sequence).unwrap(), "AGATCGGA");
+        assert_eq!(detected.supporting_reads, 6);
+        assert!(detect_adapter_from_read_through(&adapter_ends, 7).is_none());
+        assert!(
+            detect_adapter_from_read_through(&[b"ACGT".to_vec(), b"TGCA".to_vec()], 2).is_none()
+        );
+    }
+
+    #[test]
+    fn test_detect_adapter_ignores_duplicated_insert() {
+        use super::detect_adapter;
+        // amplicon reads: the same insert at the same position in every read
+        let mut reads = vec![b"GAGGGTGCTTCAGAGTATGTATACCACTGG".to_vec(); 8];
+        reads.push(b"GTAGGATACGGCGGAGGGCACGTCAATACG".to_vec());
+        assert!(detect_adapter(&reads, 3).is_none());
+        // reads with shorter inserts running into the adapter are still detected
+        reads.extend(
+            [
+                "GAGGGTGCTTCAGATCGGAAGAGCACACGT",
+                "GAGGGTGCTTCAGAGAGATCGGAAGAGCAC",
+                "GAGGGTGCAGATCGGAAGAGCACACGTCTG",
+                "GAGGGTGCTTCAGAGTAAGATCGGAAGAGC",
+            ]
+            .iter()
+            .map(|x| x.as_bytes().to_vec()),
+        );
+        let detected = detect_adapter(&reads, 3).unwrap();
+        assert!(std::str::from_utf8(&detected.sequence)
+            .unwrap()
+            .starts_with("AGATCGGAAGAGC"));
+        assert_eq!(detected.supporting_reads, 4);
+    }
+
+    #[test]
+    fn test_align_iupac() {
+        use super::{align_iupac, Anchor};
+        let hit = |query: &[u8], reference: &[u8], anchor: Anchor, max_error_rate: f32| {
+            align_iupac(query, reference, anchor, max_error_rate)
+                .map(|hit| (hit.start, hit.end, hit.errors))
+        };
+        let adapter = b"AGATCGGAAGAGC";
+        assert_eq!(
+            hit(adapter, b"TTTTAGATCGGAAGAGCTTT", Anchor::Anywhere, 0.1),
+            Some((4, 17, 0))
+        );
+        // deletion
+        assert_eq!(
+            hit(adapter, b"TTTTAGATCGAAGAGCTTT", Anchor::Anywhere, 0.1),
+            Some((4, 16, 1))
+        );
+        // insertion
+        assert_eq!(
+            hit(adapter, b"TTTTAGATCGGTAAGAGCTTT", Anchor::Anywhere, 0.1),
+            Some((4, 18, 1))
+        );
+        assert_eq!(
+            hit(adapter, b"TTTTAGATCGGTAAGAGCTTT", Anchor::Anywhere, 0.0),
+            None
+        );
+        assert_eq!(
+            hit(b"AGATC", b"AGTTCGG", Anchor::Left, 0.2),
+            Some((0, 5, 1))
+        );
+        assert_eq!(
+            hit(b"AGATC", b"GGAGTC", Anchor::Right, 0.2),
+            Some((2, 6, 1))
+        );
+        assert_eq!(
+            hit(b"NGATC", b"GGTGATCG", Anchor::Anywhere, 0.0),
+            Some((2, 7, 0))
+        );
+    }
+
+    #[test]
+    fn test_align_adapter_3prime() {
+        use super::align_adapter_3prime;
+        let hit = |reference: &[u8], min_length: usize| {
+            align_adapter_3prime(b"AGATCGGAAGAGC", reference, min_length, 0.1)
+                .map(|hit| (hit.start, hit.end, hit.query_len, hit.errors))
+        };
+        // complete adapter, followed by other bases
+        assert_eq!(hit(b"CCCCCCCAGATCGGAAGAGCTTTT", 5), Some((7, 20, 13, 0)));
+        // adapter start running off the read end, with a deletion
+        assert_eq!(hit(b"CCCCCCCAGATCGAAG", 5), Some((7, 16, 10, 1)));
+        assert_eq!(hit(b"CCCCCCCCCCCCCAGAT", 3), Some((13, 17, 4, 0)));
+        assert_eq!(hit(b"CCCCCCCCCCCCCAGAT", 5), None);
+    }
+
+    #[test]
+    fn test_align_adapter_5prime() {
+        use super::align_adapter_5prime;
+        let hit = |reference: &[u8], min_length: usize| {
+            align_adapter_5prime(b"ACACTCTTTCCCTACACGAC", reference, min_length, 0.1)
+                .map(|hit| (hit.start, hit.end, hit.query_len, hit.errors))
+        };
+        // complete adapter, with bases before it
+        assert_eq!(hit(b"GGACACTCTTTCCCTACACGACTTTTT", 5), Some((2, 22, 20, 0)));
+        // the end of the adapter at the read start, with one error
+        assert_eq!(hit(b"TTCCTACACGACTTTTT", 5), Some((0, 12, 12, 1)));
+        assert_eq!(hit(b"CGACTTTTTTTT", 3), Some((0, 4, 4, 0)));
+        assert_eq!(hit(b"CGACTTTTTTTT", 5), None);
+    }
+
+    #[test]
+    fn test_for_each_canonical_kmer() {
+        use super::for_each_canonical_kmer;
+        let kmers = |seq: &[u8], k: usize| {
+            let mut res = Vec::new();
+            for_each_canonical_kmer(seq, k, |kmer| res.push(kmer));
+            res
+        };
+        // ACGT is its own reverse complement
+        assert_eq!(kmers(b"ACGT", 4), vec![0b00_01_10_11]);
+        // AC / GT are reverse complements of each other
+        assert_eq!(kmers(b"ACGTN", 2), vec![0b0001, 0b0110, 0b0001]);
+        assert_eq!(kmers(b"aaNaa", 2), vec![0, 0]);
+        assert_eq!(kmers(b"TTTT", 4), kmers(b"AAAA", 4));
+        assert_eq!(kmers(b"ACGTTGCAAGGCTTAGCCATGCAATTGCAGGTAA", 32).len(), 3);
+        assert_eq!(
+            kmers(b"ACGTTGCAAGGCTTAGCCATGCAATTGCAGGTAA", 21),
+            kmers(b"TTACCTGCAATTGCATGGCTAAGCCTTGCAACGT", 21)
+                .into_iter()
+                .rev()
+                .collect::<Vec<_>>()
+        );
+        assert!(kmers(b"ACG", 4).is_empty());
+    }
 }
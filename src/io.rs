@@ -1,9 +1,17 @@
 use crate::{
-    config::Target,
-    dna::{Anchor, Hits},
+    config::{InputFormat, Target},
+    dna::{Anchor, Hit, Hits},
+};
+use anyhow::{bail, Context, Result};
+use parallel_gzip::ParallelGzipReader;
+pub(crate) mod parallel_gzip;
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Read},
+    ops::Range,
+    path::Path,
 };
-use anyhow::{Context, Result};
-use std::{collections::HashMap, io::Read, ops::Range, path::Path};
 
 #[derive(Debug, Copy, Clone)]
 pub struct Position {
@@ -114,6 +122,14 @@ impl FastQElement {
         self.get_mut(local_buffer).reverse();
     }
 
+    /// For when the local buffer is appended to another one at `offset`
+    fn shift(&mut self, offset: usize) {
+        if let FastQElement::Local(element) = self {
+            element.start += offset;
+            element.end += offset;
+        }
+    }
+
     fn reverse_complement(&mut self, local_buffer: &mut [u8]) {
         let m = self.get_mut(local_buffer);
         let reversed = crate::dna::reverse_complement_iupac(m);
@@ -163,6 +179,21 @@ impl FastQRead {
         self.qual.cut_end(self.qual.len() - len);
         assert_eq!(self.seq.len(), self.qual.len());
     }
+
+    fn shift(&mut self, offset: usize) {
+        self.name.shift(offset);
+        self.seq.shift(offset);
+        self.qual.shift(offset);
+    }
+
+    /// A copy that no longer refers to the block's buffer
+    fn to_owned_read(&self, block: &[u8]) -> FastQRead {
+        FastQRead {
+            name: FastQElement::Owned(self.name.get(block).to_vec()),
+            seq: FastQElement::Owned(self.seq.get(block).to_vec()),
+            qual: FastQElement::Owned(self.qual.get(block).to_vec()),
+        }
+    }
 }
 
 pub struct FastQBlock {
@@ -188,6 +219,26 @@ impl FastQBlock {
         self.entries.is_empty()
     }
 
+    /// Move the reads of `other` to the end of this block
+    fn append(&mut self, other: FastQBlock) {
+        let offset = self.block.len();
+        self.block.extend_from_slice(&other.block);
+        for mut read in other.entries {
+            read.shift(offset);
+            self.entries.push(read);
+        }
+    }
+
+    /// (owned) copies of the reads where `keep` is false
+    /// (see `removed_positions` for their indices)
+    fn removed_reads(&self, keep: &[bool]) -> impl Iterator<Item = FastQRead> + '_ {
+        self.entries
+            .iter()
+            .zip(keep)
+            .filter(|(_, keep)| !**keep)
+            .map(|(read, _)| read.to_owned_read(&self.block))
+    }
+
     #[must_use]
     pub fn get(&self, index: usize) -> WrappedFastQRead {
         WrappedFastQRead(&self.entries[index], &self.block)
@@ -274,39 +325,18 @@ impl FastQBlock {
             let mut right: Vec<FastQRead> = self.entries.drain(target_reads_per_block..).collect();
             let left = self.entries;
             //let (left, right) = self.entries.split_at(target_reads_per_block);
-            let buffer_split_pos = match &left.iter().last().unwrap().qual {
-                FastQElement::Owned(_) => match &right.first().unwrap().name {
-                    FastQElement::Owned(_) => {
-                        unreachable!("Left and write were owned, that shouldn't happen")
-                    }
-                    FastQElement::Local(position) => position.start,
-                },
-                FastQElement::Local(position) => position.end,
+            // partial reads are only ever completed while we're below
+            // target_reads_per_block, so the right side's names all live in the buffer.
+            // Other owned elements (FASTA qualities / multi line sequences) stay as they are.
+            let buffer_split_pos = match &right.first().unwrap().name {
+                FastQElement::Owned(_) => {
+                    unreachable!("Right side started with an owned read, that shouldn't happen")
+                }
+                FastQElement::Local(position) => position.start,
             };
             for entry in &mut right {
-                match &mut entry.name {
-                    FastQElement::Owned(_) => {
-                        unreachable!()
-                    }
-                    FastQElement::Local(position) => {
-                        position.start -= buffer_split_pos;
-                        position.end -= buffer_split_pos;
-                    }
-                }
-                match &mut entry.seq {
-                    FastQElement::Owned(_) => {
-                        unreachable!()
-                    }
-                    FastQElement::Local(position) => {
-                        position.start -= buffer_split_pos;
-                        position.end -= buffer_split_pos;
-                    }
-                }
-                match &mut entry.qual {
-                    FastQElement::Owned(_) => {
-                        unreachable!()
-                    }
-                    FastQElement::Local(position) => {
+                for element in [&mut entry.name, &mut entry.seq, &mut entry.qual] {
+                    if let FastQElement::Local(position) = element {
                         position.start -= buffer_split_pos;
                         position.end -= buffer_split_pos;
                     }
@@ -485,6 +515,27 @@ impl WrappedFastQRead<'_> {
         out.push(b'\n');
     }
 
+    /// FASTA record, sequence wrapped every `line_width` bases (None: single line)
+    pub fn append_as_fasta(&self, out: &mut Vec<u8>, line_width: Option<usize>) {
+        let name = self.0.name.get(self.1);
+        let seq = self.0.seq.get(self.1);
+        out.push(b'>');
+        out.extend(name);
+        out.push(b'\n');
+        match line_width {
+            Some(line_width) => {
+                for line in seq.chunks(line_width) {
+                    out.extend(line);
+                    out.push(b'\n');
+                }
+            }
+            None => {
+                out.extend(seq);
+                out.push(b'\n');
+            }
+        }
+    }
+
     pub fn find_iupac(
         &self,
         query: &[u8],
@@ -495,6 +546,17 @@ impl WrappedFastQRead<'_> {
         let seq = self.0.seq.get(self.1);
         crate::dna::find_iupac(seq, query, anchor, max_mismatches, target)
     }
+
+    pub fn find_iupac_with_indels(
+        &self,
+        query: &[u8],
+        anchor: Anchor,
+        max_error_rate: f32,
+        target: Target,
+    ) -> Option<Hits> {
+        let seq = self.0.seq.get(self.1);
+        crate::dna::find_iupac_with_indels(seq, query, anchor, max_error_rate, target)
+    }
 }
 
 impl WrappedFastQReadMut<'_> {
@@ -597,6 +659,50 @@ impl WrappedFastQReadMut<'_> {
         }
     }
 
+    /// Cut off a 3' adapter, tolerating indels (see `dna::align_adapter_3prime`)
+    pub fn trim_adapter_with_indels(
+        &mut self,
+        query: &[u8],
+        min_length: usize,
+        max_error_rate: f32,
+    ) {
+        let seq = self.seq();
+        if let Some(hit) = crate::dna::align_adapter_3prime(query, seq, min_length, max_error_rate)
+        {
+            let cut = seq.len() - hit.start;
+            self.cut_end(cut);
+        }
+    }
+
+    /// Cut the read at the first position the adapter starts at,
+    /// with the adapter either ending within the read
+    /// or running off its end (like `trim_adapter_mismatch_tail`,
+    /// but for reads shorter than the adapter as well).
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn trim_adapter_mismatch(
+        &mut self,
+        query: &[u8],
+        min_length: usize,
+        max_mismatches: usize,
+    ) {
+        let seq = self.seq();
+        // complete adapters start before any partial one
+        let complete_start = if query.len() >= min_length {
+            seq.windows(query.len()).position(|window| {
+                bio::alignment::distance::hamming(window, query) as usize <= max_mismatches
+            })
+        } else {
+            None
+        };
+        let cut = match complete_start {
+            Some(start) => Some(seq.len() - start),
+            None => longest_suffix_that_is_a_prefix(seq, query, max_mismatches, min_length),
+        };
+        if let Some(cut) = cut {
+            self.cut_end(cut);
+        }
+    }
+
     #[allow(clippy::too_many_lines)]
     pub fn trim_poly_base_suffix(
         &mut self,
@@ -792,6 +898,81 @@ pub struct FastQBlocksCombined {
     pub index2: Option<FastQBlock>,
     pub output_tags: Option<Vec<u16>>, // used by Demultiplex
     pub tags: Option<HashMap<String, Vec<Option<Hits>>>>,
+    /// molecules removed by filters - if output.output_rejected is set
+    pub rejected: Option<Box<FastQBlocksCombined>>,
+    /// surviving mates of pairs removed by a paired_end = 'EmitOrphans' filter.
+    /// In the rejected block: the failing mates of those pairs.
+    pub singletons: Option<Box<Singletons>>,
+}
+
+/// Reads whose mate failed a filter. Unlike the other blocks,
+/// read1 and read2 are not of the same length.
+pub struct Singletons {
+    pub read1: FastQBlock,
+    pub read2: FastQBlock,
+    /// per singleton: how many molecules of the block preceded it,
+    /// so that Head can cut the singletons along with the molecules.
+    /// (Not maintained for the rejected singletons)
+    read1_positions: Vec<usize>,
+    read2_positions: Vec<usize>,
+}
+
+impl Singletons {
+    fn empty() -> Singletons {
+        Singletons {
+            read1: FastQBlock::empty(),
+            read2: FastQBlock::empty(),
+            read1_positions: Vec::new(),
+            read2_positions: Vec::new(),
+        }
+    }
+
+    /// Update the positions after the molecules where `keep` is false were removed
+    pub fn molecules_retained(&mut self, keep: &[bool]) {
+        let mut kept_before = Vec::with_capacity(keep.len() + 1);
+        kept_before.push(0);
+        for k in keep {
+            kept_before.push(kept_before.last().unwrap() + usize::from(*k));
+        }
+        for pos in self
+            .read1_positions
+            .iter_mut()
+            .chain(self.read2_positions.iter_mut())
+        {
+            *pos = kept_before[(*pos).min(keep.len())];
+        }
+    }
+
+    /// Move the singletons of `other` to the end,
+    /// `other`'s block following the `molecule_count` molecules of ours.
+    fn append(&mut self, other: Singletons, molecule_count: usize) {
+        self.read1.append(other.read1);
+        self.read2.append(other.read2);
+        self.read1_positions
+            .extend(other.read1_positions.iter().map(|pos| pos + molecule_count));
+        self.read2_positions
+            .extend(other.read2_positions.iter().map(|pos| pos + molecule_count));
+    }
+
+    /// Drop the singletons that did not precede one of the first `len` molecules
+    fn truncate(&mut self, len: usize) {
+        for (block, positions) in [
+            (&mut self.read1, &mut self.read1_positions),
+            (&mut self.read2, &mut self.read2_positions),
+        ] {
+            let mut iter = positions.iter();
+            block.entries.retain(|_| *iter.next().unwrap() < len);
+            positions.retain(|pos| *pos < len);
+        }
+    }
+}
+
+/// the indices where `keep` is false
+fn removed_positions(keep: &[bool]) -> impl Iterator<Item = usize> + '_ {
+    keep.iter()
+        .enumerate()
+        .filter(|(_, keep)| !**keep)
+        .map(|(ii, _)| ii)
 }
 
 impl FastQBlocksCombined {
@@ -809,6 +990,213 @@ impl FastQBlocksCombined {
                 None
             },
             tags: None,
+            rejected: self
+                .rejected
+                .as_ref()
+                .map(|rejected| Box::new(rejected.empty())),
+            singletons: None,
+        }
+    }
+
+    /// An empty block that still carries what the filters
+    /// removed so far (rejected reads, singletons)
+    #[must_use]
+    pub fn into_empty(mut self) -> FastQBlocksCombined {
+        let mut res = self.empty();
+        res.rejected = self.rejected.take();
+        res.singletons = self.singletons.take();
+        if let Some(singletons) = res.singletons.as_mut() {
+            singletons.molecules_retained(&vec![false; self.len()]);
+        }
+        res
+    }
+
+    /// Move everything in `other` (molecules, tags, rejected reads, singletons)
+    /// to the end of this block.
+    /// For steps that collect several blocks before processing them.
+    pub fn append(&mut self, other: FastQBlocksCombined) {
+        let molecule_count = self.len();
+        self.read1.append(other.read1);
+        for (target, source) in [
+            (self.read2.as_mut(), other.read2),
+            (self.index1.as_mut(), other.index1),
+            (self.index2.as_mut(), other.index2),
+        ] {
+            if let (Some(target), Some(source)) = (target, source) {
+                target.append(source);
+            }
+        }
+        if let (Some(output_tags), Some(other_output_tags)) =
+            (self.output_tags.as_mut(), other.output_tags)
+        {
+            output_tags.extend(other_output_tags);
+        }
+        if let (Some(tags), Some(other_tags)) = (self.tags.as_mut(), other.tags) {
+            for (label, hits) in other_tags {
+                tags.entry(label).or_default().extend(hits);
+            }
+        }
+        if let (Some(rejected), Some(other_rejected)) = (self.rejected.as_mut(), other.rejected) {
+            rejected.append(*other_rejected);
+        }
+        if let Some(other_singletons) = other.singletons {
+            self.singletons
+                .get_or_insert_with(|| Box::new(Singletons::empty()))
+                .append(*other_singletons, molecule_count);
+        }
+    }
+
+    /// Move the molecules (and their tags) to a new block, leaving an empty one
+    /// that still carries what the filters removed so far (rejected reads, singletons).
+    /// For steps that hold molecules back.
+    pub fn take_molecules(&mut self) -> FastQBlocksCombined {
+        let mut res = self.empty();
+        res.tags = self.tags.as_ref().map(|tags| {
+            tags.keys()
+                .map(|label| (label.clone(), Vec::new()))
+                .collect()
+        });
+        res.rejected = self.rejected.as_mut().map(|rejected| {
+            let empty = rejected.empty();
+            Box::new(std::mem::replace(rejected.as_mut(), empty))
+        });
+        res.singletons = self.singletons.take();
+        if let Some(singletons) = res.singletons.as_mut() {
+            singletons.molecules_retained(&vec![false; self.len()]);
+        }
+        std::mem::replace(self, res)
+    }
+
+    /// Drop the singletons that came after the first `len` molecules.
+    /// For Head, before cutting the block to `len`.
+    pub fn truncate_singletons(&mut self, len: usize) {
+        if let Some(singletons) = self.singletons.as_mut() {
+            singletons.truncate(len);
+        }
+    }
+
+    /// Copy read1 where only read1 passed, and read2 where only read2 passed,
+    /// to the singletons.
+    /// Their failing mates go to the rejected singletons (if we are collecting rejected reads).
+    pub fn store_singletons(&mut self, passed_read1: &[bool], passed_read2: &[bool]) {
+        let read2 = self.read2.as_ref().expect("singletons need read2");
+        let not_only_read1: Vec<bool> = passed_read1
+            .iter()
+            .zip(passed_read2)
+            .map(|(a, b)| !(*a && !*b))
+            .collect();
+        let not_only_read2: Vec<bool> = passed_read1
+            .iter()
+            .zip(passed_read2)
+            .map(|(a, b)| !(*b && !*a))
+            .collect();
+        let singletons = self
+            .singletons
+            .get_or_insert_with(|| Box::new(Singletons::empty()));
+        singletons
+            .read1
+            .entries
+            .extend(self.read1.removed_reads(&not_only_read1));
+        singletons
+            .read1_positions
+            .extend(removed_positions(&not_only_read1));
+        singletons
+            .read2
+            .entries
+            .extend(read2.removed_reads(&not_only_read2));
+        singletons
+            .read2_positions
+            .extend(removed_positions(&not_only_read2));
+        if let Some(rejected) = self.rejected.as_mut() {
+            let rejected_singletons = rejected
+                .singletons
+                .get_or_insert_with(|| Box::new(Singletons::empty()));
+            rejected_singletons
+                .read1
+                .entries
+                .extend(self.read1.removed_reads(&not_only_read2));
+            rejected_singletons
+                .read2
+                .entries
+                .extend(read2.removed_reads(&not_only_read1));
+        }
+    }
+
+    /// Copy the molecules where `keep` is false to the rejected block
+    /// (if we are collecting rejected reads), together with their barcode (if demultiplexing).
+    pub fn store_rejected(&mut self, keep: &[bool]) {
+        if let Some(rejected) = self.rejected.as_mut() {
+            if let Some(rejected_tags) = rejected.output_tags.as_mut() {
+                match self.output_tags.as_ref() {
+                    Some(output_tags) => rejected_tags.extend(
+                        output_tags
+                            .iter()
+                            .zip(keep)
+                            .filter(|(_, keep)| !**keep)
+                            .map(|(tag, _)| *tag),
+                    ),
+                    None => rejected_tags.extend(
+                        removed_positions(keep)
+                            .map(|_| crate::demultiplex::REJECTED_BEFORE_DEMULTIPLEX),
+                    ),
+                }
+            }
+            rejected
+                .read1
+                .entries
+                .extend(self.read1.removed_reads(keep));
+            for (target, source) in [
+                (rejected.read2.as_mut(), self.read2.as_ref()),
+                (rejected.index1.as_mut(), self.index1.as_ref()),
+                (rejected.index2.as_mut(), self.index2.as_ref()),
+            ] {
+                if let (Some(target), Some(source)) = (target, source) {
+                    target.entries.extend(source.removed_reads(keep));
+                }
+            }
+        }
+    }
+
+    /// The number of molecules, read1 singletons and read2 singletons,
+    /// to pass to `append_comment_to_owned_names` later on.
+    #[must_use]
+    pub fn len_including_singletons(&self) -> [usize; 3] {
+        let (singletons_read1, singletons_read2) = self
+            .singletons
+            .as_ref()
+            .map_or((0, 0), |s| (s.read1.len(), s.read2.len()));
+        [self.len(), singletons_read1, singletons_read2]
+    }
+
+    /// Append ' `comment`' to the names of all molecules (and singletons)
+    /// added since `len_including_singletons` returned `start`.
+    /// Only for blocks of owned reads, such as the rejected ones.
+    pub fn append_comment_to_owned_names(&mut self, start: [usize; 3], comment: &[u8]) {
+        let [start_molecules, start_singletons_read1, start_singletons_read2] = start;
+        let (singletons_read1, singletons_read2) = match self.singletons.as_mut() {
+            Some(singletons) => (Some(&mut singletons.read1), Some(&mut singletons.read2)),
+            None => (None, None),
+        };
+        for (block, start) in [
+            (Some(&mut self.read1), start_molecules),
+            (self.read2.as_mut(), start_molecules),
+            (self.index1.as_mut(), start_molecules),
+            (self.index2.as_mut(), start_molecules),
+            (singletons_read1, start_singletons_read1),
+            (singletons_read2, start_singletons_read2),
+        ] {
+            let Some(block) = block else {
+                continue;
+            };
+            for read in &mut block.entries[start..] {
+                match &mut read.name {
+                    FastQElement::Owned(name) => {
+                        name.push(b' ');
+                        name.extend_from_slice(comment);
+                    }
+                    FastQElement::Local(_) => unreachable!("expected owned reads"),
+                }
+            }
         }
     }
 
@@ -870,6 +1258,12 @@ impl FastQBlocksCombined {
         if let Some(output_tags) = &mut self.output_tags {
             output_tags.drain(range.clone());
         }
+        if let Some(singletons) = self.singletons.as_mut() {
+            let keep: Vec<bool> = (0..self.read1.len() + range.len())
+                .map(|ii| !range.contains(&ii))
+                .collect();
+            singletons.molecules_retained(&keep);
+        }
     }
 
     pub fn apply_mut<F>(&mut self, f: F)
@@ -1322,6 +1716,173 @@ pub fn parse_to_fastq_block(
     })
 }
 
+/// Parse (single or multi line) FASTA records into a `FastQBlock`.
+///
+/// Every base gets `fake_quality` as it's quality score.
+/// Single line sequences stay in the buffer, multi line sequences are joined into an owned Vec.
+///
+/// A record is only known to be complete once the next '>' has been seen,
+/// so the last record is always returned as partial read -
+/// use `finish_fasta_read` on it once the file has been exhausted.
+pub fn parse_to_fasta_block(
+    target_block: &mut FastQBlock,
+    start_offset: usize,
+    stop: usize,
+    last_status: PartialStatus,
+    last_read: Option<FastQRead>,
+    fake_quality: u8,
+) -> Result<FastQBlockParseResult> {
+    let input = &target_block.block;
+    let entries = &mut target_block.entries;
+    let mut pos = start_offset;
+    let mut last_status = last_status;
+    let mut last_read = last_read;
+    //continue where we left off
+    if last_status == PartialStatus::InName {
+        let last_read2 = last_read.as_mut().unwrap();
+        let FastQElement::Owned(name) = &mut last_read2.name else {
+            panic!("Should not happen");
+        };
+        match memchr::memchr(b'\n', &input[pos..stop]) {
+            Some(next_newline) => {
+                name.extend_from_slice(&input[pos..pos + next_newline]);
+                pos = pos + next_newline + 1;
+                last_status = PartialStatus::InSeq;
+            }
+            None => {
+                name.extend_from_slice(&input[pos..stop]);
+                return Ok(FastQBlockParseResult {
+                    status: PartialStatus::InName,
+                    partial_read: last_read,
+                });
+            }
+        }
+    }
+    if last_status == PartialStatus::InSeq {
+        let last_read2 = last_read.as_mut().unwrap();
+        let FastQElement::Owned(seq) = &mut last_read2.seq else {
+            panic!("Should not happen");
+        };
+        // '>' never occurs inside a sequence, so it does not matter whether
+        // we're continuing a line or starting a new one - it's all sequence.
+        while pos < stop && input[pos] != b'>' {
+            match memchr::memchr(b'\n', &input[pos..stop]) {
+                Some(next_newline) => {
+                    seq.extend_from_slice(&input[pos..pos + next_newline]);
+                    pos = pos + next_newline + 1;
+                }
+                None => {
+                    seq.extend_from_slice(&input[pos..stop]);
+                    pos = stop;
+                }
+            }
+        }
+        if pos >= stop {
+            return Ok(FastQBlockParseResult {
+                status: PartialStatus::InSeq,
+                partial_read: last_read,
+            });
+        }
+    }
+    if let Some(last_read) = last_read {
+        entries.push(finish_fasta_read(last_read, fake_quality)?);
+    }
+
+    let mut status = PartialStatus::NoPartial;
+    let mut partial_read = None;
+
+    while pos < stop {
+        if input[pos] == b'\n' {
+            // empty lines before the first record
+            pos += 1;
+            continue;
+        }
+        if input[pos] != b'>' {
+            bail!(
+                "Unexpected symbol where > was expected in FASTA input. Position {}, was {}",
+                pos,
+                input[pos]
+            );
+        }
+        let Some(end_of_name) = memchr::memchr(b'\n', &input[pos..stop]) else {
+            status = PartialStatus::InName;
+            partial_read = Some(FastQRead {
+                name: FastQElement::Owned(input[pos + 1..stop].to_vec()),
+                seq: FastQElement::Owned(Vec::new()),
+                qual: FastQElement::Owned(Vec::new()),
+            });
+            break;
+        };
+        let name = Position {
+            start: pos + 1,
+            end: pos + end_of_name,
+        };
+        if name.start == name.end {
+            bail!("Empty name in FASTA input (a '>' line without a name). Position {pos}");
+        }
+        pos = pos + end_of_name + 1;
+
+        // sequence lines up to the next record.
+        // The first line we reference in the buffer, we only copy if there's more than one.
+        let mut first_line: Option<Position> = None;
+        let mut joined: Option<Vec<u8>> = None;
+        while pos < stop && input[pos] != b'>' {
+            let line_end = memchr::memchr(b'\n', &input[pos..stop]).map_or(stop, |x| pos + x);
+            if line_end > pos {
+                let line = &input[pos..line_end];
+                if let Some(seq) = joined.as_mut() {
+                    seq.extend_from_slice(line);
+                } else if let Some(first) = &first_line {
+                    let mut seq = input[first.start..first.end].to_vec();
+                    seq.extend_from_slice(line);
+                    joined = Some(seq);
+                } else {
+                    first_line = Some(Position {
+                        start: pos,
+                        end: line_end,
+                    });
+                }
+            }
+            pos = (line_end + 1).min(stop);
+        }
+        let seq = match (first_line, joined) {
+            (_, Some(joined)) => FastQElement::Owned(joined),
+            (Some(first_line), None) => FastQElement::Local(first_line),
+            (None, None) => FastQElement::Owned(Vec::new()),
+        };
+        if pos >= stop {
+            // the next chunk might hold more sequence lines
+            status = PartialStatus::InSeq;
+            partial_read = Some(FastQRead {
+                // can't call new, must not verify yet
+                name: FastQElement::Owned(input[name.start..name.end].to_vec()),
+                seq: FastQElement::Owned(seq.get(input).to_vec()),
+                qual: FastQElement::Owned(Vec::new()),
+            });
+            break;
+        }
+        let qual = FastQElement::Owned(vec![fake_quality; seq.len()]);
+        entries.push(FastQRead::new(FastQElement::Local(name), seq, qual));
+    }
+
+    Ok(FastQBlockParseResult {
+        status,
+        partial_read,
+    })
+}
+
+/// Complete a partial FASTA read (from `parse_to_fasta_block`) by assigning it's qualities.
+///
+/// Names that were split between buffers are only checked here.
+pub fn finish_fasta_read(mut read: FastQRead, fake_quality: u8) -> Result<FastQRead> {
+    if read.name.is_empty() {
+        bail!("Empty name in FASTA input (a '>' line without a name)");
+    }
+    read.qual = FastQElement::Owned(vec![fake_quality; read.seq.len()]);
+    read.verify();
+    Ok(read)
+}
+
 pub struct FastQParser<'a> {
     readers: Vec<NifflerReader<'a>>,
     current_reader: usize,
@@ -1330,6 +1891,12 @@ pub struct FastQParser<'a> {
     target_reads_per_block: usize,
     last_partial: Option<FastQRead>,
     last_status: PartialStatus,
+    input_format: InputFormat,
+    // Auto until we have seen the first byte of the current reader
+    current_format: InputFormat,
+    fasta_fake_quality: u8,
+    // bytes read from the current reader so far - for error messages
+    current_file_offset: usize,
 }
 
 impl<'a> FastQParser<'a> {
@@ -1338,6 +1905,8 @@ impl<'a> FastQParser<'a> {
         readers: Vec<NifflerReader<'a>>,
         target_reads_per_block: usize,
         buf_size: usize,
+        input_format: InputFormat,
+        fasta_fake_quality: u8,
     ) -> FastQParser<'a> {
         FastQParser {
             readers,
@@ -1350,9 +1919,29 @@ impl<'a> FastQParser<'a> {
             target_reads_per_block,
             last_partial: None,
             last_status: PartialStatus::NoPartial,
+            input_format,
+            current_format: input_format,
+            fasta_fake_quality,
+            current_file_offset: 0,
         }
     }
 
+    /// A FASTA file's last record is only complete once it's file has ended.
+    fn finish_current_file(&mut self) -> Result<()> {
+        if self.current_format == InputFormat::Fasta {
+            if let Some(partial) = self.last_partial.take() {
+                self.current_block
+                    .as_mut()
+                    .unwrap()
+                    .entries
+                    .push(finish_fasta_read(partial, self.fasta_fake_quality)?);
+            }
+            self.last_status = PartialStatus::NoPartial;
+        }
+        self.current_format = self.input_format;
+        Ok(())
+    }
+
     pub fn parse(&mut self) -> Result<(FastQBlock, bool)> {
         let mut was_final = false;
         //consume until we have at least target_reads_per_block (if at all possible)
@@ -1374,25 +1963,55 @@ impl<'a> FastQParser<'a> {
             let read = self.readers[self.current_reader]
                 .read(&mut self.current_block.as_mut().unwrap().block[start..])?;
             //dbg!(read);
+            // where we are, for error messages
+            let file_no = self.current_reader + 1;
+            let file_offset = self.current_file_offset + read;
+            let error_context = || {
+                format!("Error parsing input file {file_no} (of this segment) before byte {file_offset}")
+            };
+            self.current_file_offset = file_offset;
             if read == 0 {
                 //println!("advancing file");
+                self.finish_current_file().with_context(error_context)?;
                 self.current_reader += 1;
+                self.current_file_offset = 0;
                 if self.current_reader >= self.readers.len() {
                     //println!("beyond final file");
                     was_final = true;
                     break;
                 }
             }
+            if read > 0 && self.current_format == InputFormat::Auto {
+                let first_byte = self.current_block.as_ref().unwrap().block[start];
+                self.current_format = if first_byte == b'>' {
+                    InputFormat::Fasta
+                } else {
+                    InputFormat::Fastq
+                };
+            }
             start += read;
             //println!("read {} bytes", read);
             // read more data
-            let parse_result = parse_to_fastq_block(
-                self.current_block.as_mut().unwrap(),
-                block_start,
-                start,
-                self.last_status,
-                self.last_partial.take(),
-            )?;
+            let parse_result = if self.current_format == InputFormat::Fasta {
+                parse_to_fasta_block(
+                    self.current_block.as_mut().unwrap(),
+                    block_start,
+                    start,
+                    self.last_status,
+                    self.last_partial.take(),
+                    self.fasta_fake_quality,
+                )
+                .with_context(error_context)?
+            } else {
+                parse_to_fastq_block(
+                    self.current_block.as_mut().unwrap(),
+                    block_start,
+                    start,
+                    self.last_status,
+                    self.last_partial.take(),
+                )
+                .with_context(error_context)?
+            };
             self.last_status = parse_result.status;
             self.last_partial = parse_result.partial_read;
         }
@@ -1418,6 +2037,7 @@ pub type NifflerReader<'a> = Box<dyn Read + 'a + Send>;
 pub type InputSet<'a> = FourReadsCombined<NifflerReader<'a>>;
 pub type InputSetVec<'a> = FourReadsCombined<Vec<NifflerReader<'a>>>;
 
+#[derive(Default)]
 pub struct InputFiles<'a> {
     sets: Vec<InputSet<'a>>,
 }
@@ -1458,14 +2078,53 @@ impl<'a> InputFiles<'a> {
     }
 }
 
+/// '-' means 'read from stdin'.
+/// (/dev/stdin, /dev/fd/N and named pipes work as regular files)
+#[must_use]
+pub fn is_stdin(filename: impl AsRef<Path>) -> bool {
+    filename.as_ref() == Path::new("-")
+}
+
+fn open_raw_file(filename: impl AsRef<Path>) -> Result<Box<dyn Read + Send>> {
+    if is_stdin(&filename) {
+        Ok(Box::new(std::io::stdin()))
+    } else {
+        let fh = std::fs::File::open(filename.as_ref())
+            .context(format!("Could not open file {:?}", filename.as_ref()))?;
+        Ok(Box::new(fh))
+    }
+}
+
 pub fn open_file(filename: impl AsRef<Path>) -> Result<Box<dyn Read + Send>> {
-    let fh = std::fs::File::open(filename.as_ref())
-        .context(format!("Could not open file {:?}", filename.as_ref()))?;
+    let fh = open_raw_file(filename)?;
+    let wrapped = niffler::send::get_reader(fh)?;
+    Ok(wrapped.0)
+}
+
+/// Like `open_file`, but BGZF files are decompressed on `decompression_threads` threads
+fn open_input_file(
+    filename: impl AsRef<Path>,
+    decompression_threads: usize,
+) -> Result<Box<dyn Read + Send>> {
+    let mut fh = std::io::BufReader::new(open_raw_file(&filename)?);
+    let start = fh
+        .fill_buf()
+        .with_context(|| format!("Could not read from {:?}", filename.as_ref()))?;
+    if decompression_threads > 1 && parallel_gzip::is_bgzf(start) {
+        return Ok(Box::new(ParallelGzipReader::new(
+            Box::new(fh),
+            decompression_threads,
+        )));
+    }
     let wrapped = niffler::send::get_reader(Box::new(fh))?;
     Ok(wrapped.0)
 }
 
-pub fn open_input_files<'a>(input_config: &crate::config::Input) -> Result<InputFiles<'a>> {
+pub fn open_input_files<'a>(
+    input_config: &crate::config::Input,
+    decompression_threads: usize,
+) -> Result<InputFiles<'a>> {
+    let open_file = |filename: &String| open_input_file(filename, decompression_threads);
     let mut sets = Vec::new();
     for (ii, read1_filename) in (input_config.read1).iter().enumerate() {
         // we may assume all the others are either of the same length, or None
@@ -1500,6 +2159,268 @@ pub fn open_input_files<'a>(input_config: &crate::config::Input) -> Result<Input
     Ok(InputFiles { sets })
 }
 
+/// One block worth of molecules read from unaligned BAM/SAM files.
+pub struct UnalignedBlock {
+    pub read1: FastQBlock,
+    pub read2: Option<FastQBlock>,
+    /// imported aux tags (no location), keyed by their two letter name.
+    pub tags: Option<HashMap<String, Vec<Option<Hits>>>>,
+}
+
+impl UnalignedBlock {
+    fn new(paired: bool, import_tags: &[String]) -> UnalignedBlock {
+        UnalignedBlock {
+            read1: FastQBlock::empty(),
+            read2: paired.then(FastQBlock::empty),
+            tags: if import_tags.is_empty() {
+                None
+            } else {
+                Some(
+                    import_tags
+                        .iter()
+                        .map(|tag_name| (tag_name.clone(), Vec::new()))
+                        .collect(),
+                )
+            },
+        }
+    }
+}
+
+struct UnalignedRecord {
+    read: FastQRead,
+    tags: Vec<Option<Vec<u8>>>,
+}
+
+/// Turns (unaligned) SAM records into blocks of molecules,
+/// pairing up read1/read2 records if requested.
+struct UnalignedBlockBuilder<'a> {
+    paired: bool,
+    import_tags: &'a [String],
+    aux_tags: Vec<noodles::sam::alignment::record::data::field::Tag>,
+    fake_quality: u8,
+    target_reads_per_block: usize,
+    // (is first segment, record) waiting for it's mate
+    pending: Option<(bool, UnalignedRecord)>,
+    current: UnalignedBlock,
+}
+
+impl<'a> UnalignedBlockBuilder<'a> {
+    fn new(
+        paired: bool,
+        import_tags: &'a [String],
+        fake_quality: u8,
+        target_reads_per_block: usize,
+    ) -> UnalignedBlockBuilder<'a> {
+        let aux_tags = import_tags
+            .iter()
+            .map(|tag_name| {
+                let tag_name = tag_name.as_bytes();
+                noodles::sam::alignment::record::data::field::Tag::new(tag_name[0], tag_name[1])
+            })
+            .collect();
+        UnalignedBlockBuilder {
+            paired,
+            import_tags,
+            aux_tags,
+            fake_quality,
+            target_reads_per_block,
+            pending: None,
+            current: UnalignedBlock::new(paired, import_tags),
+        }
+    }
+
+    /// Returns a block once it has reached target_reads_per_block
+    fn push<R: noodles::sam::alignment::Record>(
+        &mut self,
+        record: &R,
+    ) -> Result<Option<UnalignedBlock>> {
+        use noodles::sam::alignment::record::data::field::Value;
+        let flags = record.flags()?;
+        if flags.is_secondary() || flags.is_supplementary() {
+            return Ok(None);
+        }
+        let name: Vec<u8> = record.name().map(|x| x.to_vec()).unwrap_or_default();
+        let mut seq: Vec<u8> = record.sequence().iter().collect();
+        let qual = record
+            .quality_scores()
+            .iter()
+            .collect::<std::io::Result<Vec<u8>>>()?;
+        // missing qualities are 0xFF in BAM, and empty in SAM
+        let mut qual: Vec<u8> = if qual.is_empty() || qual.iter().all(|x| *x == 0xFF) {
+            vec![self.fake_quality; seq.len()]
+        } else {
+            qual.iter().map(|x| x.saturating_add(33)).collect()
+        };
+        if flags.is_reverse_complemented() {
+            // restore the orientation the sequencer reported
+            seq = crate::dna::reverse_complement_iupac(&seq);
+            qual.reverse();
+        }
+        let mut tags = Vec::new();
+        for (tag_name, aux_tag) in self.import_tags.iter().zip(&self.aux_tags) {
+            let value = match record.data().get(aux_tag).transpose()? {
+                None => None,
+                Some(Value::String(value)) => Some(value.to_vec()),
+                Some(Value::Character(value)) => Some(vec![value]),
+                Some(_) => bail!(
+                    "BAM aux tag {tag_name} of read {} is neither a string nor a character, can't import it.",
+                    std::str::from_utf8(&name).unwrap_or("utf-8 error")
+                ),
+            };
+            tags.push(value);
+        }
+        if seq.len() != qual.len() {
+            bail!(
+                "Sequence and quality length differ in BAM read {}",
+                std::str::from_utf8(&name).unwrap_or("utf-8 error")
+            );
+        }
+        let record = UnalignedRecord {
+            read: FastQRead::new(
+                FastQElement::Owned(name),
+                FastQElement::Owned(seq),
+                FastQElement::Owned(qual),
+            ),
+            tags,
+        };
+
+        if !self.paired {
+            if flags.is_segmented() {
+                bail!(
+                    "BAM input contains paired reads. Set input.interleaved = true to read them."
+                );
+            }
+            self.add(record, None);
+        } else {
+            if !flags.is_segmented() {
+                bail!(
+                    "input.interleaved is set, but the BAM input contains an unpaired read: {}",
+                    std::str::from_utf8(record.read.name.get(&[])).unwrap_or("utf-8 error")
+                );
+            }
+            let is_first_segment = flags.is_first_segment();
+            match self.pending.take() {
+                None => self.pending = Some((is_first_segment, record)),
+                Some((pending_is_first_segment, pending)) => {
+                    if pending_is_first_segment == is_first_segment
+                        || pending.read.name.get(&[]) != record.read.name.get(&[])
+                    {
+                        bail!(
+                            "BAM input: expected read1 and read2 records of a pair to follow each other. Offending read: {}",
+                            std::str::from_utf8(record.read.name.get(&[])).unwrap_or("utf-8 error")
+                        );
+                    }
+                    if is_first_segment {
+                        self.add(record, Some(pending));
+                    } else {
+                        self.add(pending, Some(record));
+                    }
+                }
+            }
+        }
+        if self.current.read1.len() >= self.target_reads_per_block {
+            Ok(Some(std::mem::replace(
+                &mut self.current,
+                UnalignedBlock::new(self.paired, self.import_tags),
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn add(&mut self, read1: UnalignedRecord, read2: Option<UnalignedRecord>) {
+        let read2_tags = read2.as_ref().map(|x| x.tags.clone());
+        if let Some(tags) = self.current.tags.as_mut() {
+            for (ii, (tag_name, value)) in self.import_tags.iter().zip(read1.tags).enumerate() {
+                // mates usually carry the same tags - but only one of them needs to
+                let value = value.or_else(|| read2_tags.as_ref().and_then(|x| x[ii].clone()));
+                tags.get_mut(tag_name).unwrap().push(value.map(|sequence| {
+                    Hits::new_multiple(vec![Hit {
+                        location: None,
+                        sequence,
+                    }])
+                }));
+            }
+        }
+        self.current.read1.entries.push(read1.read);
+        if let Some(read2) = read2 {
+            self.current
+                .read2
+                .as_mut()
+                .expect("read2 block missing")
+                .entries
+                .push(read2.read);
+        }
+    }
+
+    fn finish(self) -> Result<UnalignedBlock> {
+        if let Some((_, pending)) = self.pending {
+            bail!(
+                "BAM input ended with an unpaired read: {}",
+                std::str::from_utf8(pending.read.name.get(&[])).unwrap_or("utf-8 error")
+            );
+        }
+        Ok(self.current)
+    }
+}
+
+/// Read unaligned BAM (or SAM, decided on the extension) files
+/// and pass blocks of up to `target_reads_per_block` molecules to `send`.
+///
+/// Read1/read2 are split by the records' flags (if `paired`).
+/// Secondary and supplementary records are skipped.
+/// `send` returns false once nobody is listening anymore.
+pub fn parse_unaligned_bam(
+    filenames: &[String],
+    paired: bool,
+    import_tags: &[String],
+    target_reads_per_block: usize,
+    fake_quality: u8,
+    mut send: impl FnMut(UnalignedBlock) -> bool,
+) -> Result<()> {
+    let mut builder =
+        UnalignedBlockBuilder::new(paired, import_tags, fake_quality, target_reads_per_block);
+    for filename in filenames {
+        let mut fh = std::io::BufReader::new(open_raw_file(filename)?);
+        let is_sam = if is_stdin(filename) {
+            // no file extension to go by - BAM is always BGZF (=gzip) compressed
+            let first_bytes = fh.fill_buf().context("Failed to read from stdin")?;
+            !first_bytes.starts_with(&[0x1f, 0x8b])
+        } else {
+            Path::new(filename)
+                .extension()
+                .is_some_and(|ext| ext == "sam")
+        };
+        if is_sam {
+            let mut reader = noodles::sam::io::Reader::new(fh);
+            reader
+                .read_header()
+                .with_context(|| format!("Could not read SAM header from {filename}"))?;
+            for result in reader.records() {
+                if let Some(block) = builder.push(&result?)? {
+                    if !send(block) {
+                        return Ok(());
+                    }
+                }
+            }
+        } else {
+            let mut reader = noodles::bam::io::Reader::new(fh);
+            reader
+                .read_header()
+                .with_context(|| format!("Could not read BAM header from {filename}"))?;
+            for result in reader.records() {
+                if let Some(block) = builder.push(&result?)? {
+                    if !send(block) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+    send(builder.finish()?);
+    Ok(())
+}
+
 #[allow(clippy::cast_possible_truncation)]
 fn longest_suffix_that_is_a_prefix(
     seq: &[u8],
@@ -1520,6 +2441,32 @@ fn longest_suffix_that_is_a_prefix(
     None
 }
 
+/// Read all (name, sequence) records of a (small) FASTA or FASTQ file,
+/// e.g. a list of adapters. Names are cut at the first whitespace.
+pub fn read_named_sequences(filename: impl AsRef<Path>) -> Result<Vec<(String, Vec<u8>)>> {
+    let file = open_file(filename)?;
+    let mut parser = FastQParser::new(vec![file], 10_000, 100_000, InputFormat::Auto, b'I');
+    let mut res = Vec::new();
+    loop {
+        let (block, was_final) = parser.parse()?;
+        for read in &block.entries {
+            let name = read.name.get(&block.block);
+            let name = name
+                .split(u8::is_ascii_whitespace)
+                .next()
+                .unwrap_or_default();
+            res.push((
+                String::from_utf8_lossy(name).to_string(),
+                read.seq.get(&block.block).to_vec(),
+            ));
+        }
+        if was_final {
+            break;
+        }
+    }
+    Ok(res)
+}
+
 /// Given a fastq or bam file, run a call back on all read names
 pub fn apply_to_read_names(
     filename: impl AsRef<Path>,
@@ -1551,7 +2498,7 @@ pub fn apply_to_read_names(
         }
     } else {
         let file = open_file(filename)?;
-        let mut parser = FastQParser::new(vec![file], 10_000, 100_000);
+        let mut parser = FastQParser::new(vec![file], 10_000, 100_000, InputFormat::Auto, b'I');
         loop {
             let (block, was_final) = parser.parse()?;
             for read in block.entries {
@@ -1594,7 +2541,7 @@ pub fn apply_to_read_sequences(
         }
     } else {
         let file = open_file(filename)?;
-        let mut parser = FastQParser::new(vec![file], 10_000, 100_000);
+        let mut parser = FastQParser::new(vec![file], 10_000, 100_000, InputFormat::Auto, b'I');
         loop {
             let (block, was_final) = parser.parse()?;
             for read in block.entries {
@@ -2105,6 +3052,32 @@ mod test {
         assert!(wrapped.seq() == read2.seq.get(&block2));
     }
 
+    #[test]
+    fn test_trim_adapter_mismatch() {
+        fn trim(seq: &str, query: &str, min_length: usize, max_mismatches: usize) -> String {
+            let mut read = get_owned2(seq.as_bytes());
+            let mut block = Vec::new();
+            let mut wrapped = WrappedFastQReadMut(&mut read, &mut block);
+            wrapped.trim_adapter_mismatch(query.as_bytes(), min_length, max_mismatches);
+            assert_eq!(wrapped.seq().len(), wrapped.qual().len());
+            std::str::from_utf8(wrapped.seq()).unwrap().to_string()
+        }
+        // adapter running off the end
+        assert_eq!(trim("TTTTTAGATCGG", "AGATCGGAAG", 5, 0), "TTTTT");
+        // adapter ending within the read
+        assert_eq!(trim("TTTTTAGATCGGAAGCCC", "AGATCGGAAG", 5, 0), "TTTTT");
+        assert_eq!(trim("TTTTTAGTTCGGAAGCCC", "AGATCGGAAG", 5, 1), "TTTTT");
+        assert_eq!(
+            trim("TTTTTAGTTCGGAAGCCC", "AGATCGGAAG", 5, 0),
+            "TTTTTAGTTCGGAAGCCC"
+        );
+        // too short a remainder
+        assert_eq!(trim("TTTTTTTTAGAT", "AGATCGGAAG", 5, 0), "TTTTTTTTAGAT");
+        assert_eq!(trim("AGA", "AGATCGGAAG", 5, 0), "AGA");
+        // all adapter
+        assert_eq!(trim("AGATCGGAAGAGAT", "AGATCGGAAG", 5, 0), "");
+    }
+
     #[test]
     fn test_trim_polybase_min_longer_than_seq() {
         let (mut read, mut block) = get_local();
@@ -2126,6 +3099,8 @@ mod test {
 
             output_tags: None,
             tags: None,
+            rejected: None,
+            singletons: None,
         });
         assert!(blocks.is_empty());
     }
@@ -2139,6 +3114,8 @@ mod test {
             index2: Some(FastQBlock::empty()),
             output_tags: None,
             tags: None,
+            rejected: None,
+            singletons: None,
         };
         empty.sanity_check();
     }
@@ -2159,6 +3136,8 @@ mod test {
             index2: Some(FastQBlock::empty()),
             output_tags: None,
             tags: None,
+            rejected: None,
+            singletons: None,
         };
         empty.sanity_check();
     }
@@ -2187,6 +3166,8 @@ mod test {
             index2: Some(FastQBlock::empty()),
             output_tags: None,
             tags: None,
+            rejected: None,
+            singletons: None,
         };
         empty.sanity_check();
     }
@@ -2222,6 +3203,8 @@ mod test {
             index2: Some(FastQBlock::empty()),
             output_tags: None,
             tags: None,
+            rejected: None,
+            singletons: None,
         };
         empty.sanity_check();
     }
@@ -2264,7 +3247,153 @@ mod test {
             }),
             output_tags: Some(vec![]),
             tags: None,
+            rejected: None,
+            singletons: None,
         };
         empty.sanity_check();
     }
+
+    fn parse_all(input: &'static [u8], format: InputFormat, buf_size: usize) -> Vec<FastQRead> {
+        let reader: NifflerReader = Box::new(input);
+        let mut parser = FastQParser::new(vec![reader], 2, buf_size, format, b'I');
+        let mut res = Vec::new();
+        loop {
+            let (block, was_final) = parser.parse().unwrap();
+            for read in &block.entries {
+                res.push(FastQRead {
+                    name: FastQElement::Owned(read.name.get(&block.block).to_vec()),
+                    seq: FastQElement::Owned(read.seq.get(&block.block).to_vec()),
+                    qual: FastQElement::Owned(read.qual.get(&block.block).to_vec()),
+                });
+            }
+            if was_final {
+                break;
+            }
+        }
+        res
+    }
+
+    fn assert_reads(reads: &[FastQRead], should: &[(&[u8], &[u8], &[u8])]) {
+        assert_eq!(reads.len(), should.len());
+        for (read, (name, seq, qual)) in reads.iter().zip(should) {
+            assert_eq!(read.name.get(&[]), *name);
+            assert_eq!(read.seq.get(&[]), *seq);
+            assert_eq!(read.qual.get(&[]), *qual);
+        }
+    }
+
+    #[test]
+    fn test_parse_fasta_single_line() {
+        let input = b">r1 comment\nACGT\n>r2\nGG\n>r3\nTTTTT\n";
+        //tiny buffers so we hit every partial state
+        for buf_size in [1, 2, 3, 5, 7, 100] {
+            let reads = parse_all(input, InputFormat::Fasta, buf_size);
+            assert_reads(
+                &reads,
+                &[
+                    (b"r1 comment", b"ACGT", b"IIII"),
+                    (b"r2", b"GG", b"II"),
+                    (b"r3", b"TTTTT", b"IIIII"),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_fasta_multi_line() {
+        // no trailing newline, empty record, blank lines
+        let input = b">r1\nACGT\nAC\n\nGA\n>empty\n>r3\nTT\nT";
+        for buf_size in [1, 2, 3, 5, 7, 100] {
+            let reads = parse_all(input, InputFormat::Auto, buf_size);
+            assert_reads(
+                &reads,
+                &[
+                    (b"r1", b"ACGTACGA", b"IIIIIIII"),
+                    (b"empty", b"", b""),
+                    (b"r3", b"TTT", b"III"),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_autodetect_fastq() {
+        let input = b"@r1\nACGT\n+\nABCD\n@r2\nGG\n+\nEF\n";
+        let reads = parse_all(input, InputFormat::Auto, 100);
+        assert_reads(&reads, &[(b"r1", b"ACGT", b"ABCD"), (b"r2", b"GG", b"EF")]);
+    }
+
+    #[test]
+    fn test_parse_fasta_mixed_files() {
+        let readers: Vec<NifflerReader> = vec![
+            Box::new(&b">a\nAC\nGT"[..]),
+            Box::new(&b"@b\nCC\n+\nBB\n"[..]),
+            Box::new(&b">c\nGG\n"[..]),
+        ];
+        let mut parser = FastQParser::new(readers, 10, 3, InputFormat::Auto, b'#');
+        let (block, was_final) = parser.parse().unwrap();
+        assert!(was_final);
+        let get = |ii: usize| {
+            let read = block.get(ii);
+            (
+                read.name().to_vec(),
+                read.seq().to_vec(),
+                read.qual().to_vec(),
+            )
+        };
+        assert_eq!(block.len(), 3);
+        assert_eq!(get(0), (b"a".to_vec(), b"ACGT".to_vec(), b"####".to_vec()));
+        assert_eq!(get(1), (b"b".to_vec(), b"CC".to_vec(), b"BB".to_vec()));
+        assert_eq!(get(2), (b"c".to_vec(), b"GG".to_vec(), b"##".to_vec()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected symbol where > was expected")]
+    fn test_parse_fasta_garbage() {
+        parse_all(b"r1\nACGT\n", InputFormat::Fasta, 100);
+    }
+
+    #[test]
+    fn test_parse_fasta_empty_name() {
+        // within the buffer, split between buffers, at the end of the file
+        for input in [
+            &b">r1\nACGT\n>\nGG\n>r3\nT\n"[..],
+            b">r1\nA\n>\n",
+            b">r1\nACGT\n>",
+        ] {
+            for buf_size in [1, 2, 3, 5, 7, 100] {
+                let reader: NifflerReader = Box::new(input);
+                let mut parser =
+                    FastQParser::new(vec![reader], 2, buf_size, InputFormat::Fasta, b'I');
+                let err = loop {
+                    match parser.parse() {
+                        Ok((_block, was_final)) => assert!(!was_final, "expected an error"),
+                        Err(err) => break err,
+                    }
+                };
+                assert!(format!("{err:?}").contains("Empty name in FASTA input"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_append_as_fasta() {
+        let block = FastQBlock {
+            block: Vec::new(),
+            entries: vec![FastQRead {
+                name: FastQElement::Owned(b"r1 comment".to_vec()),
+                seq: FastQElement::Owned(b"ACGTACGTA".to_vec()),
+                qual: FastQElement::Owned(b"IIIIIIIII".to_vec()),
+            }],
+        };
+        let mut out = Vec::new();
+        block.get(0).append_as_fasta(&mut out, None);
+        assert_eq!(out, b">r1 comment\nACGTACGTA\n");
+        out.clear();
+        block.get(0).append_as_fasta(&mut out, Some(4));
+        assert_eq!(out, b">r1 comment\nACGT\nACGT\nA\n");
+        out.clear();
+        block.get(0).append_as_fasta(&mut out, Some(9));
+        assert_eq!(out, b">r1 comment\nACGTACGTA\n");
+    }
 }
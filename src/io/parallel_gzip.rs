@@ -0,0 +1,567 @@
+//! Multithreaded (de)compression of BGZF files.
+//!
+//! BGZF (and any gzip stream whose members carry the 'BC' extra field)
+//! tells us the compressed size of each member up front, so we can cut the
+//! file into members on one thread and inflate them on several others.
+//! Members without a block size (e.g. a plain gzip file concatenated to a BGZF file)
+//! are inflated on the reading thread - from there on we're no faster than
+//! a regular `MultiGzDecoder`.
+//!
+//! Writing is the mirror image: the data is cut into (at most) 64kb blocks,
+//! which are deflated independently, producing BGZF that htslib can index.
+//! All writers share one pool of compression threads.
+use crossbeam::channel::{bounded, Receiver, Sender};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+
+const GZIP_HEADER_LEN: usize = 10;
+/// how many members may be in flight (read but not yet consumed) per worker
+const MEMBERS_IN_FLIGHT_PER_WORKER: usize = 4;
+/// chunk size when falling back to sequential decompression
+const FALLBACK_CHUNK_SIZE: usize = 1024 * 1024;
+/// uncompressed bytes per BGZF block - what htslib uses,
+/// leaves room for incompressible data to stay below the 64kb block limit
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+/// the empty block that marks the end of a BGZF file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+type DecompressedMember = io::Result<Vec<u8>>;
+
+/// Does this (peeked) data look like the start of a BGZF file?
+#[must_use]
+pub fn is_bgzf(start: &[u8]) -> bool {
+    start.len() >= 16
+        && start[0..4] == [0x1f, 0x8b, 0x08, 0x04] // gzip, deflate, FEXTRA
+        && start[12..14] == *b"BC"
+        && start[14..16] == [2, 0]
+}
+
+/// Decompresses BGZF input on `worker_count` threads,
+/// returning the data in order.
+pub struct ParallelGzipReader {
+    /// None once all members were consumed (or a worker died)
+    pending: Option<Receiver<Receiver<DecompressedMember>>>,
+    current: Vec<u8>,
+    pos: usize,
+    /// the member splitting thread and the workers
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl ParallelGzipReader {
+    pub fn new(reader: Box<dyn BufRead + Send>, worker_count: usize) -> Self {
+        let worker_count = worker_count.max(1);
+        let (job_tx, job_rx) = bounded::<(Vec<u8>, Sender<DecompressedMember>)>(worker_count);
+        let (pending_tx, pending_rx) = bounded(worker_count * MEMBERS_IN_FLIGHT_PER_WORKER);
+        let mut threads = Vec::with_capacity(worker_count + 1);
+        for _ in 0..worker_count {
+            let job_rx = job_rx.clone();
+            threads.push(std::thread::spawn(move || {
+                for (member, result_tx) in job_rx {
+                    //a closed result channel just means the reader was dropped
+                    let _ = result_tx.send(decompress_member(&member));
+                }
+            }));
+        }
+        threads.push(std::thread::spawn(move || {
+            split_members(reader, &job_tx, &pending_tx);
+        }));
+        ParallelGzipReader {
+            pending: Some(pending_rx),
+            current: Vec::new(),
+            pos: 0,
+            threads,
+        }
+    }
+
+    /// Stop the threads (dropping `pending` makes the splitter stop,
+    /// which stops the workers), wait for them,
+    /// and turn a panic in any of them into an error.
+    fn join_threads(&mut self) -> io::Result<()> {
+        self.pending = None;
+        let mut res = Ok(());
+        for handle in self.threads.drain(..) {
+            if let Err(panic) = handle.join() {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(ToString::to_string)
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                if res.is_ok() {
+                    res = Err(io::Error::other(format!(
+                        "gzip decompression thread panicked: {message}"
+                    )));
+                }
+            }
+        }
+        res
+    }
+}
+
+impl Drop for ParallelGzipReader {
+    fn drop(&mut self) {
+        let _ = self.join_threads();
+    }
+}
+
+impl Read for ParallelGzipReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.current.len() {
+            let Some(pending) = self.pending.as_ref() else {
+                return Ok(0);
+            };
+            let Ok(result_rx) = pending.recv() else {
+                // all members consumed
+                self.join_threads()?;
+                return Ok(0);
+            };
+            let Ok(result) = result_rx.recv() else {
+                self.join_threads()?;
+                return Err(io::Error::other("gzip decompression worker died"));
+            };
+            self.current = result?;
+            self.pos = 0;
+        }
+        let len = buf.len().min(self.current.len() - self.pos);
+        buf[..len].copy_from_slice(&self.current[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn decompress_member(member: &[u8]) -> DecompressedMember {
+    // ISIZE, the uncompressed size (mod 2^32) is the last 4 bytes of each member
+    let uncompressed_size = member.len().checked_sub(4).map_or(0, |start| {
+        u32::from_le_bytes(member[start..].try_into().unwrap())
+    });
+    let mut out = Vec::with_capacity(uncompressed_size as usize);
+    flate2::read::GzDecoder::new(member).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Read members from `reader`, hand them to the workers,
+/// and queue their result channels (in file order) for the consumer.
+fn split_members(
+    mut reader: Box<dyn BufRead + Send>,
+    job_tx: &Sender<(Vec<u8>, Sender<DecompressedMember>)>,
+    pending_tx: &Sender<Receiver<DecompressedMember>>,
+) {
+    loop {
+        match read_member(&mut reader) {
+            Ok(Member::Eof) => return,
+            Ok(Member::Sized(member)) => {
+                let (result_tx, result_rx) = bounded(1);
+                if pending_tx.send(result_rx).is_err() || job_tx.send((member, result_tx)).is_err()
+                {
+                    return; // reader dropped
+                }
+            }
+            Ok(Member::Unsized(already_read)) => {
+                decompress_sequentially(already_read, reader, pending_tx);
+                return;
+            }
+            Err(e) => {
+                let (result_tx, result_rx) = bounded(1);
+                result_tx.send(Err(e)).expect("fresh channel can't be full");
+                let _ = pending_tx.send(result_rx);
+                return;
+            }
+        }
+    }
+}
+
+/// A gzip member without BGZF block size - inflate the rest of the file on this thread
+fn decompress_sequentially(
+    already_read: Vec<u8>,
+    reader: Box<dyn BufRead + Send>,
+    pending_tx: &Sender<Receiver<DecompressedMember>>,
+) {
+    let mut decoder =
+        flate2::read::MultiGzDecoder::new(io::Cursor::new(already_read).chain(reader));
+    loop {
+        let mut chunk = Vec::with_capacity(FALLBACK_CHUNK_SIZE);
+        let result = (&mut decoder)
+            .take(FALLBACK_CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk);
+        let done = !matches!(result, Ok(n) if n > 0);
+        let (result_tx, result_rx) = bounded(1);
+        result_tx
+            .send(result.map(|_| chunk))
+            .expect("fresh channel can't be full");
+        if pending_tx.send(result_rx).is_err() || done {
+            return;
+        }
+    }
+}
+
+enum Member {
+    Eof,
+    /// A complete member (header to trailer)
+    Sized(Vec<u8>),
+    /// The bytes read so far of a member without BGZF block size
+    Unsized(Vec<u8>),
+}
+
+fn read_member(reader: &mut Box<dyn BufRead + Send>) -> io::Result<Member> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(Member::Eof);
+    }
+    let mut header = vec![0u8; GZIP_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if header[0..2] != [0x1f, 0x8b] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Trailing garbage after gzip data",
+        ));
+    }
+    let has_extra = header[3] & 0x04 != 0;
+    if !has_extra {
+        return Ok(Member::Unsized(header));
+    }
+    let mut xlen = [0u8; 2];
+    reader.read_exact(&mut xlen)?;
+    header.extend_from_slice(&xlen);
+    let xlen = usize::from(u16::from_le_bytes(xlen));
+    let mut extra = vec![0u8; xlen];
+    reader.read_exact(&mut extra)?;
+    header.extend_from_slice(&extra);
+
+    let Some(block_size) = find_block_size(&extra) else {
+        return Ok(Member::Unsized(header));
+    };
+    let mut member = header;
+    let remaining = block_size
+        .checked_sub(member.len())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid BGZF block size"))?;
+    let start = member.len();
+    member.resize(block_size, 0);
+    reader.read_exact(&mut member[start..start + remaining])?;
+    Ok(Member::Sized(member))
+}
+
+/// The total member size from the BGZF 'BC' extra subfield (BSIZE + 1)
+fn find_block_size(extra: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    while offset + 4 <= extra.len() {
+        let subfield_len = usize::from(u16::from_le_bytes([extra[offset + 2], extra[offset + 3]]));
+        if extra[offset..offset + 2] == *b"BC" && subfield_len == 2 && offset + 6 <= extra.len() {
+            let bsize = usize::from(u16::from_le_bytes([extra[offset + 4], extra[offset + 5]]));
+            return Some(bsize + 1);
+        }
+        offset += 4 + subfield_len;
+    }
+    None
+}
+
+type CompressionJob = (Vec<u8>, flate2::Compression, Sender<Vec<u8>>);
+
+/// BGZF compression threads, shared by any number of `BgzfWriter`s,
+/// so that the thread count does not grow with the number of output files.
+///
+/// The workers stop once the pool and all writers using it have been dropped.
+#[derive(Debug, Clone)]
+pub struct BgzfCompressionPool {
+    job_tx: Sender<CompressionJob>,
+    worker_count: usize,
+}
+
+impl BgzfCompressionPool {
+    pub fn new(worker_count: usize) -> Self {
+        let worker_count = worker_count.max(1);
+        let (job_tx, job_rx) = bounded::<CompressionJob>(worker_count);
+        for _ in 0..worker_count {
+            let job_rx = job_rx.clone();
+            std::thread::spawn(move || {
+                for (block, level, result_tx) in job_rx {
+                    let _ = result_tx.send(compress_block(&block, level));
+                }
+            });
+        }
+        BgzfCompressionPool {
+            job_tx,
+            worker_count,
+        }
+    }
+}
+
+/// Compresses into BGZF on the threads of a `BgzfCompressionPool`.
+///
+/// Call `finish` to get errors from writing the last blocks,
+/// dropping finishes on a best effort basis.
+pub struct BgzfWriter<W: Write> {
+    inner: Option<W>,
+    buffer: Vec<u8>,
+    level: flate2::Compression,
+    job_tx: Option<Sender<CompressionJob>>,
+    pending: VecDeque<Receiver<Vec<u8>>>,
+    max_pending: usize,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W, level: flate2::Compression, pool: &BgzfCompressionPool) -> Self {
+        BgzfWriter {
+            inner: Some(inner),
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+            level,
+            job_tx: Some(pool.job_tx.clone()),
+            pending: VecDeque::new(),
+            max_pending: pool.worker_count * MEMBERS_IN_FLIGHT_PER_WORKER,
+        }
+    }
+
+    /// hand the buffered data to the workers
+    fn submit_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::replace(&mut self.buffer, Vec::with_capacity(BGZF_BLOCK_SIZE));
+        let (result_tx, result_rx) = bounded(1);
+        self.job_tx
+            .as_ref()
+            .expect("BgzfWriter used after finish")
+            .send((block, self.level, result_tx))
+            .map_err(|_| io::Error::other("gzip compression worker died"))?;
+        self.pending.push_back(result_rx);
+        while self.pending.len() > self.max_pending {
+            self.write_next_block()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the oldest block, and write it
+    fn write_next_block(&mut self) -> io::Result<()> {
+        if let Some(result_rx) = self.pending.pop_front() {
+            let compressed = result_rx
+                .recv()
+                .map_err(|_| io::Error::other("gzip compression worker died"))?;
+            self.inner
+                .as_mut()
+                .expect("BgzfWriter used after finish")
+                .write_all(&compressed)?;
+        }
+        Ok(())
+    }
+
+    fn write_all_pending(&mut self) -> io::Result<()> {
+        self.submit_block()?;
+        while !self.pending.is_empty() {
+            self.write_next_block()?;
+        }
+        Ok(())
+    }
+
+    /// Write all outstanding blocks and the EOF marker
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_all_pending()?;
+        self.job_tx = None;
+        let mut inner = self.inner.take().expect("BgzfWriter finished twice");
+        inner.write_all(&BGZF_EOF)?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.submit_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_all_pending()?;
+        self.inner
+            .as_mut()
+            .expect("BgzfWriter used after finish")
+            .flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.write_all_pending();
+            if let Some(inner) = self.inner.as_mut() {
+                let _ = inner.write_all(&BGZF_EOF);
+                let _ = inner.flush();
+            }
+        }
+    }
+}
+
+/// One complete BGZF member
+fn compress_block(block: &[u8], level: flate2::Compression) -> Vec<u8> {
+    let mut encoder =
+        flate2::write::DeflateEncoder::new(Vec::with_capacity(block.len() / 2 + 64), level);
+    encoder
+        .write_all(block)
+        .expect("writing to a Vec can't fail");
+    let deflated = encoder.finish().expect("writing to a Vec can't fail");
+    // header (with BC extra field) + data + crc + isize
+    let member_size = 18 + deflated.len() + 8;
+    let mut out = Vec::with_capacity(member_size);
+    out.extend_from_slice(&[
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, b'B', b'C', 0x02,
+        0x00,
+    ]);
+    out.extend_from_slice(
+        &u16::try_from(member_size - 1)
+            .expect("BGZF block too large")
+            .to_le_bytes(),
+    );
+    out.extend_from_slice(&deflated);
+    let mut crc = flate2::Crc::new();
+    crc.update(block);
+    out.extend_from_slice(&crc.sum().to_le_bytes());
+    out.extend_from_slice(
+        &u32::try_from(block.len())
+            .expect("BGZF block too large")
+            .to_le_bytes(),
+    );
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn bgzf_member(data: &[u8]) -> Vec<u8> {
+        compress_block(data, flate2::Compression::default())
+    }
+
+    fn plain_gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_all(compressed: Vec<u8>, worker_count: usize) -> io::Result<Vec<u8>> {
+        let mut reader = ParallelGzipReader::new(
+            Box::new(io::BufReader::new(io::Cursor::new(compressed))),
+            worker_count,
+        );
+        let mut out = Vec::new();
+        //small reads, so we cross member boundaries
+        let mut buf = [0u8; 7];
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..read]);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_bgzf_in_order() {
+        let mut compressed = Vec::new();
+        let mut should = Vec::new();
+        for ii in 0..100 {
+            let data = format!("@read{ii}\nACGT\n+\nIIII\n").repeat(ii % 7);
+            compressed.extend(bgzf_member(data.as_bytes()));
+            should.extend_from_slice(data.as_bytes());
+        }
+        compressed.extend(bgzf_member(b"")); // EOF marker
+        assert!(is_bgzf(&compressed));
+        for worker_count in [1, 2, 5] {
+            assert_eq!(read_all(compressed.clone(), worker_count).unwrap(), should);
+        }
+    }
+
+    #[test]
+    fn test_bgzf_followed_by_plain_gzip() {
+        let mut compressed = bgzf_member(b"hello ");
+        compressed.extend(plain_gzip(b"world"));
+        compressed.extend(bgzf_member(b"!"));
+        assert_eq!(read_all(compressed, 3).unwrap(), b"hello world!");
+    }
+
+    #[test]
+    fn test_plain_gzip_is_not_bgzf() {
+        assert!(!is_bgzf(&plain_gzip(b"hello")));
+        assert!(!is_bgzf(b"@read1\nAGTC\n+\nIIII\n"));
+    }
+
+    #[test]
+    fn test_bgzf_truncated() {
+        let mut compressed = bgzf_member(b"hello world");
+        compressed.truncate(compressed.len() - 5);
+        assert!(read_all(compressed, 2).is_err());
+    }
+
+    #[test]
+    fn test_bgzf_corrupt_member() {
+        let mut compressed = bgzf_member(b"hello ");
+        let mut broken = bgzf_member(b"world");
+        let len = broken.len();
+        broken[len - 8] ^= 0xff; // crc mismatch, detected in the worker
+        compressed.extend(broken);
+        compressed.extend(bgzf_member(b"!"));
+        assert!(read_all(compressed, 2).is_err());
+    }
+
+    #[test]
+    fn test_bgzf_writer_round_trip() {
+        let data: Vec<u8> = (0..200_000u32)
+            .flat_map(|ii| format!("@read{ii}\nACGT\n+\nIIII\n").into_bytes())
+            .collect();
+        for worker_count in [1, 3] {
+            let pool = BgzfCompressionPool::new(worker_count);
+            let mut writer = BgzfWriter::new(Vec::new(), flate2::Compression::fast(), &pool);
+            for chunk in data.chunks(10_000) {
+                writer.write_all(chunk).unwrap();
+            }
+            let compressed = writer.finish().unwrap();
+            assert!(is_bgzf(&compressed));
+            assert!(compressed.ends_with(&BGZF_EOF));
+            assert_eq!(read_all(compressed.clone(), worker_count).unwrap(), data);
+            //and it's valid (multi member) gzip
+            let mut out = Vec::new();
+            flate2::read::MultiGzDecoder::new(&compressed[..])
+                .read_to_end(&mut out)
+                .unwrap();
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn test_bgzf_writer_empty() {
+        let pool = BgzfCompressionPool::new(2);
+        let compressed = BgzfWriter::new(Vec::new(), flate2::Compression::default(), &pool)
+            .finish()
+            .unwrap();
+        assert_eq!(compressed, BGZF_EOF);
+    }
+
+    #[test]
+    fn test_bgzf_writers_sharing_a_pool() {
+        let pool = BgzfCompressionPool::new(2);
+        let data: Vec<Vec<u8>> = (0..5u32)
+            .map(|writer_no| {
+                (0..50_000u32)
+                    .flat_map(|ii| format!("@read{writer_no}_{ii}\nACGT\n+\nIIII\n").into_bytes())
+                    .collect()
+            })
+            .collect();
+        let mut writers: Vec<_> = data
+            .iter()
+            .map(|_| BgzfWriter::new(Vec::new(), flate2::Compression::fast(), &pool))
+            .collect();
+        drop(pool); // the writers keep the workers alive
+        for chunk_no in 0..data[0].len().div_ceil(10_000) {
+            for (writer, data) in writers.iter_mut().zip(data.iter()) {
+                let chunk = &data[chunk_no * 10_000..((chunk_no + 1) * 10_000).min(data.len())];
+                writer.write_all(chunk).unwrap();
+            }
+        }
+        for (writer, data) in writers.into_iter().zip(data.iter()) {
+            let compressed = writer.finish().unwrap();
+            assert_eq!(&read_all(compressed, 2).unwrap(), data);
+        }
+    }
+}
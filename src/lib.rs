@@ -8,7 +8,7 @@ use crossbeam::channel::bounded;
 use ex::Wrapper;
 use flate2::write::GzEncoder;
 use sha2::Digest;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
@@ -21,16 +21,17 @@ mod dna;
 pub mod io;
 mod transformations;
 
-use config::{Config, FileFormat};
+use config::{CompressionFormat, Config, FileFormat};
 pub use io::FastQRead;
 pub use io::{open_input_files, InputFiles, InputSet};
 
-use crate::demultiplex::Demultiplexed;
+use crate::demultiplex::{DemultiplexInfo, Demultiplexed, REJECTED_BEFORE_DEMULTIPLEX};
 
 enum Writer<'a> {
     Raw(BufWriter<std::fs::File>),
     Gzip(GzEncoder<BufWriter<std::fs::File>>),
     Zstd(zstd::stream::AutoFinishEncoder<'a, BufWriter<std::fs::File>>),
+    Bgzf(io::parallel_gzip::BgzfWriter<BufWriter<std::fs::File>>),
     Stdout(BufWriter<std::io::Stdout>),
 }
 
@@ -40,6 +41,7 @@ impl std::fmt::Debug for Writer<'_> {
             Writer::Raw(_) => write!(f, "Writer::Raw"),
             Writer::Gzip(_) => write!(f, "Writer::Gzip"),
             Writer::Zstd(_) => write!(f, "Writer::Zstd"),
+            Writer::Bgzf(_) => write!(f, "Writer::Bgzf"),
             Writer::Stdout(_) => write!(f, "Writer::Stdout"),
         }
     }
@@ -51,6 +53,7 @@ impl Write for Writer<'_> {
             Writer::Raw(inner) => inner.write(buf),
             Writer::Gzip(inner) => inner.write(buf),
             Writer::Zstd(inner) => inner.write(buf),
+            Writer::Bgzf(inner) => inner.write(buf),
             Writer::Stdout(inner) => inner.write(buf),
         }
     }
@@ -60,36 +63,76 @@ impl Write for Writer<'_> {
             Writer::Raw(inner) => inner.flush(),
             Writer::Gzip(inner) => inner.flush(),
             Writer::Zstd(inner) => inner.flush(),
+            Writer::Bgzf(inner) => inner.flush(),
             Writer::Stdout(inner) => inner.flush(),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+enum ReadFormat {
+    Fastq,
+    Fasta { line_width: Option<usize> },
+}
+
+impl ReadFormat {
+    fn from_output_config(output_config: &config::Output) -> Self {
+        match output_config.format {
+            FileFormat::Fasta => ReadFormat::Fasta {
+                line_width: output_config.fasta_line_width,
+            },
+            _ => ReadFormat::Fastq,
+        }
+    }
+
+    fn append(self, read: &io::WrappedFastQRead, out: &mut Vec<u8>) {
+        match self {
+            ReadFormat::Fastq => read.append_as_fastq(out),
+            ReadFormat::Fasta { line_width } => read.append_as_fasta(out, line_width),
+        }
+    }
+}
+
 struct OutputFile<'a> {
     filename: PathBuf,
     writer: Writer<'a>,
     hasher: Option<sha2::Sha256>,
+    read_format: ReadFormat,
 }
 
 impl<'a> OutputFile<'a> {
-    fn new(filename: impl AsRef<Path>, format: FileFormat, do_hash: bool) -> Result<Self> {
+    fn new(
+        filename: impl AsRef<Path>,
+        output_config: &config::Output,
+        compression_threads: &CompressionThreads,
+    ) -> Result<Self> {
         let filename = filename.as_ref().to_owned();
         Ok(OutputFile {
             filename: filename.clone(),
-            writer: open_output_file(&filename, format)?,
-            hasher: if do_hash {
+            writer: open_output_file(
+                &filename,
+                output_config.compression_format(),
+                CompressionSettings {
+                    level: output_config.compression_level,
+                    zstd_long: output_config.zstd_long,
+                    threads: compression_threads.clone(),
+                },
+            )?,
+            hasher: if output_config.output_hash {
                 Some(sha2::Sha256::new())
             } else {
                 None
             },
+            read_format: ReadFormat::from_output_config(output_config),
         })
     }
 
-    fn new_with_writer(filename: &str, writer: Writer<'a>) -> Self {
+    fn new_with_writer(filename: &str, writer: Writer<'a>, read_format: ReadFormat) -> Self {
         OutputFile {
             filename: PathBuf::from(filename),
             writer,
             hasher: None,
+            read_format,
         }
     }
 
@@ -114,6 +157,162 @@ impl<'a> OutputFile<'a> {
                 .context("failed to flush hash output file")?;
         }
         self.writer.flush().context("failed to flush output file")?;
+        if let Writer::Bgzf(inner) = self.writer {
+            inner
+                .finish()
+                .context("failed to finish BGZF output file")?;
+        }
+        Ok(())
+    }
+}
+
+/// Unaligned BAM output - read1 and read2 go into the same file,
+/// (selected) tags become aux fields.
+struct OutputBam {
+    filename: PathBuf,
+    writer: Box<dyn noodles::sam::alignment::io::Write + Send>,
+    header: noodles::sam::Header,
+    // tag label, aux tag
+    tags: Vec<(String, noodles::sam::alignment::record::data::field::Tag)>,
+    // rejected reads: aux tag for the step that removed them
+    rejected_by_tag: Option<noodles::sam::alignment::record::data::field::Tag>,
+}
+
+impl OutputBam {
+    fn new(
+        filename: impl AsRef<Path>,
+        bam_tags: &std::collections::BTreeMap<String, String>,
+        rejected: bool,
+    ) -> Result<Self> {
+        let filename = filename.as_ref().to_owned();
+        let fh = std::fs::File::create(&filename)
+            .with_context(|| format!("Could not open file {}", filename.display()))?;
+        let mut writer: Box<dyn noodles::sam::alignment::io::Write + Send> =
+            Box::new(noodles::bam::io::Writer::new(BufWriter::new(fh)));
+        let header = noodles::sam::Header::default();
+        writer
+            .write_alignment_header(&header)
+            .context("Failed to write BAM header")?;
+        let tags = bam_tags
+            .iter()
+            .map(|(label, aux_tag)| {
+                let aux_tag = aux_tag.as_bytes();
+                (
+                    label.clone(),
+                    noodles::sam::alignment::record::data::field::Tag::new(aux_tag[0], aux_tag[1]),
+                )
+            })
+            .collect();
+        let rejected_by_tag = rejected.then(|| {
+            let [a, b] = config::REJECTED_BY_BAM_TAG;
+            noodles::sam::alignment::record::data::field::Tag::new(a, b)
+        });
+        Ok(OutputBam {
+            filename,
+            writer,
+            header,
+            tags,
+            rejected_by_tag,
+        })
+    }
+
+    fn write_block(
+        &mut self,
+        block: &io::FastQBlocksCombined,
+        demultiplex_tag: Option<u16>,
+    ) -> Result<()> {
+        use noodles::sam::alignment::record::Flags;
+        let (flags_read1, flags_read2) = if block.read2.is_some() {
+            let paired = Flags::SEGMENTED | Flags::UNMAPPED | Flags::MATE_UNMAPPED;
+            (paired | Flags::FIRST_SEGMENT, paired | Flags::LAST_SEGMENT)
+        } else {
+            (Flags::UNMAPPED, Flags::UNMAPPED)
+        };
+        for ii in 0..block.len() {
+            if let Some(demultiplex_tag) = demultiplex_tag {
+                if block.output_tags.as_ref().unwrap()[ii] != demultiplex_tag {
+                    continue;
+                }
+            }
+            let read1 = block.read1.get(ii);
+            let data = self.aux_data(block, ii, &read1);
+            self.write_read(&read1, flags_read1, data.clone())?;
+            if let Some(read2) = block.read2.as_ref() {
+                self.write_read(&read2.get(ii), flags_read2, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn aux_data(
+        &self,
+        block: &io::FastQBlocksCombined,
+        read_no: usize,
+        read1: &io::WrappedFastQRead,
+    ) -> noodles::sam::alignment::record_buf::Data {
+        use noodles::sam::alignment::record_buf::data::field::Value;
+        let mut data = noodles::sam::alignment::record_buf::Data::default();
+        if let Some(tags) = block.tags.as_ref() {
+            for (label, aux_tag) in &self.tags {
+                // tags that were not found on this read are left off
+                if let Some(hits) = tags.get(label).and_then(|values| values[read_no].as_ref()) {
+                    data.insert(
+                        *aux_tag,
+                        Value::String(hits.joined_sequence(Some(b"-")).into()),
+                    );
+                }
+            }
+        }
+        // the rejecting step was appended to the name as 'rejected_by=<step>' comment,
+        // but SAM read names end at the first space
+        if let Some(rejected_by_tag) = self.rejected_by_tag {
+            let name = read1.name();
+            let needle = b" rejected_by=";
+            if let Some(pos) = name
+                .windows(needle.len())
+                .rposition(|window| window == needle)
+            {
+                data.insert(
+                    rejected_by_tag,
+                    Value::String(name[pos + needle.len()..].into()),
+                );
+            }
+        }
+        data
+    }
+
+    fn write_read(
+        &mut self,
+        read: &io::WrappedFastQRead,
+        flags: noodles::sam::alignment::record::Flags,
+        data: noodles::sam::alignment::record_buf::Data,
+    ) -> Result<()> {
+        use noodles::sam::alignment::record_buf::{QualityScores, RecordBuf, Sequence};
+        let record = RecordBuf::builder()
+            // SAM read names end at the first space
+            .set_name(read.name_without_comment().to_vec())
+            .set_flags(flags)
+            .set_sequence(Sequence::from(read.seq().to_vec()))
+            .set_quality_scores(QualityScores::from(
+                read.qual()
+                    .iter()
+                    .map(|q| q.saturating_sub(33))
+                    .collect::<Vec<u8>>(),
+            ))
+            .set_data(data)
+            .build();
+        self.writer
+            .write_alignment_record(&self.header, &record)
+            .with_context(|| {
+                format!("Failed to write BAM record to {}", self.filename.display())
+            })?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.writer
+            .finish(&self.header)
+            .with_context(|| format!("Failed to finish BAM file {}", self.filename.display()))?;
         Ok(())
     }
 }
@@ -124,6 +323,7 @@ struct OutputFastqs<'a> {
     read2: Option<OutputFile<'a>>,
     index1: Option<OutputFile<'a>>,
     index2: Option<OutputFile<'a>>,
+    bam: Option<OutputBam>,
 }
 
 impl OutputFastqs<'_> {
@@ -141,6 +341,9 @@ impl OutputFastqs<'_> {
         if let Some(inner) = self.index2.take() {
             inner.finish()?
         }
+        if let Some(inner) = self.bam.take() {
+            inner.finish()?
+        }
         Ok(())
     }
 }
@@ -182,26 +385,108 @@ fn open_raw_output_file<'a>(path: &PathBuf) -> Result<Writer<'a>> {
     Ok(Writer::Raw(bufwriter))
 }
 
-fn open_gzip_output_file<'a>(path: &PathBuf, level: flate2::Compression) -> Result<Writer<'a>> {
+/// With a compression pool, we write BGZF
+fn open_gzip_output_file<'a>(
+    path: &PathBuf,
+    level: flate2::Compression,
+    bgzf_pool: Option<&io::parallel_gzip::BgzfCompressionPool>,
+) -> Result<Writer<'a>> {
     let fh = std::fs::File::create(path).context("Could not open file.")?;
     let buf_writer = BufWriter::new(fh);
+    if let Some(bgzf_pool) = bgzf_pool {
+        return Ok(Writer::Bgzf(io::parallel_gzip::BgzfWriter::new(
+            buf_writer, level, bgzf_pool,
+        )));
+    }
     let gz = GzEncoder::new(buf_writer, level);
     Ok(Writer::Gzip(gz))
 }
-fn open_zstd_output_file<'a>(path: &PathBuf, level: i32) -> Result<Writer<'a>> {
+fn open_zstd_output_file<'a>(
+    path: &PathBuf,
+    level: i32,
+    long_distance_matching: bool,
+    compression_threads: usize,
+) -> Result<Writer<'a>> {
     let fh = std::fs::File::create(path).context("Could not open file.")?;
     let buf_writer = BufWriter::new(fh);
-    let encoder = zstd::stream::Encoder::new(buf_writer, level)?;
+    let mut encoder = zstd::stream::Encoder::new(buf_writer, level)?;
+    if long_distance_matching {
+        encoder
+            .long_distance_matching(true)
+            .context("Failed to enable zstd long distance matching")?;
+        // what zstd --long uses. Larger windows would need a flag on decompression
+        encoder
+            .window_log(27)
+            .context("Failed to set zstd window size")?;
+    }
+    if compression_threads > 1 {
+        encoder
+            .multithread(u32::try_from(compression_threads)?)
+            .context("Failed to enable multithreaded zstd compression")?;
+    }
     let encoder = encoder.auto_finish();
     Ok(Writer::Zstd(encoder))
 }
 
-fn open_output_file<'a>(path: &PathBuf, format: FileFormat) -> Result<Writer<'a>> {
+/// The `compression_thread_count` budget, shared by all output files.
+///
+/// Gzip output files share one pool of BGZF workers.
+/// Zstd can't share its workers between encoders,
+/// so each output set gets its fraction of the threads (at least one).
+/// The default is single threaded compression.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CompressionThreads {
+    pub bgzf_pool: Option<io::parallel_gzip::BgzfCompressionPool>,
+    pub zstd_threads: usize,
+}
+
+impl CompressionThreads {
+    fn new(format: CompressionFormat, thread_count: usize, output_set_count: usize) -> Self {
+        if thread_count <= 1 {
+            return CompressionThreads::default();
+        }
+        match format {
+            CompressionFormat::Raw => CompressionThreads::default(),
+            CompressionFormat::Gzip => CompressionThreads {
+                bgzf_pool: Some(io::parallel_gzip::BgzfCompressionPool::new(thread_count)),
+                zstd_threads: 0,
+            },
+            CompressionFormat::Zstd => CompressionThreads {
+                bgzf_pool: None,
+                zstd_threads: (thread_count / output_set_count.max(1)).max(1),
+            },
+        }
+    }
+}
+
+/// Compression parameters beyond the format.
+/// Levels have been checked by `config::validate_compression_level`
+#[derive(Debug, Clone)]
+pub(crate) struct CompressionSettings {
+    pub level: Option<u8>,
+    pub zstd_long: bool,
+    pub threads: CompressionThreads,
+}
+
+fn open_output_file<'a>(
+    path: &PathBuf,
+    format: CompressionFormat,
+    settings: CompressionSettings,
+) -> Result<Writer<'a>> {
+    let level = config::effective_compression_level(format, settings.level);
     match format {
-        FileFormat::Raw => open_raw_output_file(path),
-        FileFormat::Gzip => open_gzip_output_file(path, flate2::Compression::default()),
-        FileFormat::Zstd => open_zstd_output_file(path, 5),
-        FileFormat::None => panic!("FileFormat::None is not a valid output format"),
+        CompressionFormat::Raw => open_raw_output_file(path),
+        CompressionFormat::Gzip => open_gzip_output_file(
+            path,
+            flate2::Compression::new(u32::from(level.unwrap())),
+            settings.threads.bgzf_pool.as_ref(),
+        ),
+        CompressionFormat::Zstd => open_zstd_output_file(
+            path,
+            i32::from(level.unwrap()),
+            settings.zstd_long,
+            settings.threads.zstd_threads,
+        ),
     }
 }
 
@@ -210,11 +495,23 @@ fn open_one_set_of_output_files<'a>(
     parsed_config: &Config,
     output_directory: &Path,
     infix: &str,
+    rejected: bool,
+    compression_threads: &CompressionThreads,
 ) -> Result<OutputFastqs<'a>> {
     Ok(match &parsed_config.output {
         Some(output_config) => {
             let suffix = output_config.get_suffix();
-            let include_hashes = output_config.output_hash;
+            if matches!(output_config.format, FileFormat::Bam) {
+                return Ok(OutputFastqs {
+                    bam: Some(OutputBam::new(
+                        output_directory
+                            .join(format!("{}{}.{}", output_config.prefix, infix, suffix)),
+                        &output_config.bam_tags,
+                        rejected,
+                    )?),
+                    ..Default::default()
+                });
+            }
             let (read1, read2, index1, index2) = match output_config.format {
                 FileFormat::None => (None, None, None, None),
                 _ => {
@@ -225,6 +522,7 @@ fn open_one_set_of_output_files<'a>(
                                 Some(OutputFile::new_with_writer(
                                     "stdout",
                                     Writer::Stdout(BufWriter::new(std::io::stdout())),
+                                    ReadFormat::from_output_config(output_config),
                                 )),
                                 None,
                             )
@@ -236,8 +534,8 @@ fn open_one_set_of_output_files<'a>(
                                     "{}{}_interleaved.{}",
                                     output_config.prefix, infix, suffix
                                 )),
-                                output_config.format,
-                                include_hashes,
+                                output_config,
+                                compression_threads,
                             )?);
                             (interleave, None)
                         } else {
@@ -247,8 +545,8 @@ fn open_one_set_of_output_files<'a>(
                                         "{}{}_1.{}",
                                         output_config.prefix, infix, suffix
                                     )),
-                                    output_config.format,
-                                    include_hashes,
+                                    output_config,
+                                    compression_threads,
                                 )?)
                             } else {
                                 None
@@ -262,8 +560,8 @@ fn open_one_set_of_output_files<'a>(
                                         "{}{}_2.{}",
                                         output_config.prefix, infix, suffix
                                     )),
-                                    output_config.format,
-                                    include_hashes,
+                                    output_config,
+                                    compression_threads,
                                 )?)
                             } else {
                                 None
@@ -279,8 +577,8 @@ fn open_one_set_of_output_files<'a>(
                                     "{}{}_i1.{}",
                                     output_config.prefix, infix, suffix
                                 )),
-                                output_config.format,
-                                include_hashes,
+                                output_config,
+                                compression_threads,
                             )?)
                         } else {
                             None
@@ -291,8 +589,8 @@ fn open_one_set_of_output_files<'a>(
                                     "{}{}_i2.{}",
                                     output_config.prefix, infix, suffix
                                 )),
-                                output_config.format,
-                                include_hashes,
+                                output_config,
+                                compression_threads,
                             )?)
                         } else {
                             None
@@ -307,6 +605,7 @@ fn open_one_set_of_output_files<'a>(
                 read2,
                 index1,
                 index2,
+                bam: None,
             }
         }
         None => OutputFastqs::default(),
@@ -315,6 +614,13 @@ fn open_one_set_of_output_files<'a>(
 
 struct OutputFiles<'a> {
     output_fastq: Vec<Arc<Mutex<OutputFastqs<'a>>>>,
+    /// all rejected reads - or, when demultiplexing,
+    /// those rejected before the (last) Demultiplex step
+    output_rejected: Option<Arc<Mutex<OutputFastqs<'a>>>>,
+    /// when demultiplexing: reads rejected after the (last) Demultiplex step,
+    /// indexed like `output_fastq`
+    output_rejected_demultiplexed: Vec<Arc<Mutex<OutputFastqs<'a>>>>,
+    output_singletons: Option<Arc<Mutex<OutputFastqs<'a>>>>,
     output_reports: OutputReports<'a>,
 }
 
@@ -337,37 +643,124 @@ fn open_output_files<'a>(
             json: None,
         },
     };
+    let want_rejected = parsed_config
+        .output
+        .as_ref()
+        .is_some_and(|o| o.output_rejected);
+    let want_singletons = parsed_config.transform.iter().any(|t| t.emits_orphans());
+    let barcode_output_set_count = match demultiplexed {
+        Demultiplexed::No => 0,
+        Demultiplexed::Yes(demultiplex_info) => demultiplex_info
+            .iter_outputs()
+            .map(|(_tag, output_key)| output_key)
+            .collect::<HashSet<_>>()
+            .len(),
+    };
+    let output_set_count = barcode_output_set_count.max(1)
+        + usize::from(want_rejected) * (1 + barcode_output_set_count)
+        + usize::from(want_singletons);
+    let compression_threads = match &parsed_config.output {
+        Some(output_config) => CompressionThreads::new(
+            output_config.compression_format(),
+            parsed_config.options.compression_thread_count,
+            output_set_count,
+        ),
+        None => CompressionThreads::default(),
+    };
+    let output_rejected = if want_rejected {
+        Some(Arc::new(Mutex::new(open_one_set_of_output_files(
+            parsed_config,
+            output_directory,
+            "_rejected",
+            true,
+            &compression_threads,
+        )?)))
+    } else {
+        None
+    };
+    let output_singletons = if want_singletons {
+        Some(Arc::new(Mutex::new(open_one_set_of_output_files(
+            parsed_config,
+            output_directory,
+            "_singletons",
+            false,
+            &compression_threads,
+        )?)))
+    } else {
+        None
+    };
 
     match demultiplexed {
         Demultiplexed::No => {
-            let output_files = open_one_set_of_output_files(parsed_config, output_directory, "")?;
+            let output_files = open_one_set_of_output_files(
+                parsed_config,
+                output_directory,
+                "",
+                false,
+                &compression_threads,
+            )?;
             Ok(OutputFiles {
                 output_fastq: vec![Arc::new(Mutex::new(output_files))],
+                output_rejected,
+                output_rejected_demultiplexed: Vec::new(),
+                output_singletons,
                 output_reports,
             })
         }
-        Demultiplexed::Yes(demultiplex_info) => {
-            let mut res = Vec::new();
-            let mut seen: HashMap<String, Arc<Mutex<OutputFastqs>>> = HashMap::new();
-            for (_tag, output_key) in demultiplex_info.iter_outputs() {
-                if seen.contains_key(output_key) {
-                    res.push(seen[output_key].clone());
-                } else {
-                    let output = Arc::new(Mutex::new(open_one_set_of_output_files(
-                        parsed_config,
-                        output_directory,
-                        &format!("_{output_key}"),
-                    )?));
-                    seen.insert(output_key.to_string(), output.clone());
-                    res.push(output);
-                }
-            }
-            Ok(OutputFiles {
-                output_fastq: res,
-                output_reports,
-            })
+        Demultiplexed::Yes(demultiplex_info) => Ok(OutputFiles {
+            output_fastq: open_demultiplexed_output_files(
+                parsed_config,
+                output_directory,
+                demultiplex_info,
+                false,
+                &compression_threads,
+            )?,
+            output_rejected,
+            output_rejected_demultiplexed: if want_rejected {
+                open_demultiplexed_output_files(
+                    parsed_config,
+                    output_directory,
+                    demultiplex_info,
+                    true,
+                    &compression_threads,
+                )?
+            } else {
+                Vec::new()
+            },
+            output_singletons,
+            output_reports,
+        }),
+    }
+}
+
+/// One set of output files per demultiplexed output (in `iter_outputs` order),
+/// outputs with the same name share their files.
+fn open_demultiplexed_output_files<'a>(
+    parsed_config: &Config,
+    output_directory: &Path,
+    demultiplex_info: &DemultiplexInfo,
+    rejected: bool,
+    compression_threads: &CompressionThreads,
+) -> Result<Vec<Arc<Mutex<OutputFastqs<'a>>>>> {
+    let suffix = if rejected { "_rejected" } else { "" };
+    let mut res = Vec::new();
+    let mut seen: HashMap<String, Arc<Mutex<OutputFastqs>>> = HashMap::new();
+    for (_tag, output_key) in demultiplex_info.iter_outputs() {
+        if seen.contains_key(output_key) {
+            res.push(seen[output_key].clone());
+        } else {
+            let output = Arc::new(Mutex::new(open_one_set_of_output_files(
+                parsed_config,
+                output_directory,
+                &format!("_{output_key}{suffix}"),
+                rejected,
+                compression_threads,
+            )?));
+            seen.insert(output_key.to_string(), output.clone());
+            res.push(output);
         }
     }
+    Ok(res)
 }
 
 fn parse_and_send(
@@ -375,8 +768,16 @@ fn parse_and_send(
     raw_tx: &crossbeam::channel::Sender<io::FastQBlock>,
     buffer_size: usize,
     block_size: usize,
+    input_format: config::InputFormat,
+    fasta_fake_quality: u8,
 ) {
-    let mut parser = io::FastQParser::new(readers, block_size, buffer_size);
+    let mut parser = io::FastQParser::new(
+        readers,
+        block_size,
+        buffer_size,
+        input_format,
+        fasta_fake_quality,
+    );
     loop {
         let (out_block, was_final) = parser.parse().unwrap();
         match raw_tx.send(out_block) {
@@ -397,8 +798,16 @@ fn parse_interleaved_and_send(
     raw_tx_read2: &crossbeam::channel::Sender<io::FastQBlock>,
     buffer_size: usize,
     block_size: usize,
+    input_format: config::InputFormat,
+    fasta_fake_quality: u8,
 ) {
-    let mut parser = io::FastQParser::new(readers, block_size, buffer_size);
+    let mut parser = io::FastQParser::new(
+        readers,
+        block_size,
+        buffer_size,
+        input_format,
+        fasta_fake_quality,
+    );
     loop {
         let (out_block, was_final) = parser.parse().unwrap();
         let (out_block_r1, out_block_r2) = out_block.split_interleaved();
@@ -422,6 +831,39 @@ fn parse_interleaved_and_send(
     }
 }
 
+fn parse_bam_and_send(
+    input_config: &config::Input,
+    raw_tx_read1: &crossbeam::channel::Sender<io::FastQBlock>,
+    raw_tx_read2: Option<&crossbeam::channel::Sender<io::FastQBlock>>,
+    raw_tx_tags: Option<&crossbeam::channel::Sender<HashMap<String, Vec<Option<dna::Hits>>>>>,
+    block_size: usize,
+) {
+    io::parse_unaligned_bam(
+        &input_config.read1,
+        input_config.interleaved,
+        &input_config.bam_import_tags,
+        block_size,
+        input_config.fasta_fake_quality,
+        |block| {
+            if raw_tx_read1.send(block.read1).is_err() {
+                return false;
+            }
+            if let Some(raw_tx_read2) = raw_tx_read2 {
+                if raw_tx_read2.send(block.read2.unwrap()).is_err() {
+                    return false;
+                }
+            }
+            if let Some(raw_tx_tags) = raw_tx_tags {
+                if raw_tx_tags.send(block.tags.unwrap()).is_err() {
+                    return false;
+                }
+            }
+            true
+        },
+    )
+    .unwrap();
+}
+
 struct RunStage0 {
     report_html: bool,
     report_json: bool,
@@ -447,14 +889,14 @@ impl RunStage0 {
             .to_string();
 
         let mut demultiplex_info = Demultiplexed::No;
-        let mut demultiplex_start = 0;
+        let mut stage_demultiplex_info = Vec::new();
         let input_info = transformations::InputInfo {
             has_read1: true,
-            has_read2: parsed.input.read2.is_some(),
+            has_read2: parsed.input.has_read2(),
             has_index1: parsed.input.index1.is_some(),
             has_index2: parsed.input.index2.is_some(),
         };
-        for (index, transform) in (parsed.transform).iter_mut().enumerate() {
+        for transform in &mut parsed.transform {
             let new_demultiplex_info = transform
                 .init(
                     &input_info,
@@ -464,13 +906,10 @@ impl RunStage0 {
                 )
                 .context("Transform initialize failed")?;
             if let Some(new_demultiplex_info) = new_demultiplex_info {
-                assert!(
-                    matches!(demultiplex_info, Demultiplexed::No),
-                    "Demultiplexed info already set, but new demultiplex info returned. More than one demultiplex transform not supported"
-                );
+                // further demultiplex levels refine the previous one
                 demultiplex_info = Demultiplexed::Yes(new_demultiplex_info);
-                demultiplex_start = index;
             }
+            stage_demultiplex_info.push(demultiplex_info.clone());
         }
         Ok(RunStage1 {
             report_html: self.report_html,
@@ -478,7 +917,7 @@ impl RunStage0 {
             output_directory: output_directory.to_owned(),
             output_prefix,
             demultiplex_info,
-            demultiplex_start,
+            stage_demultiplex_info,
         })
     }
 }
@@ -487,7 +926,8 @@ struct RunStage1 {
     output_prefix: String,
     output_directory: PathBuf,
     demultiplex_info: Demultiplexed,
-    demultiplex_start: usize,
+    /// the demultiplex info each stage sees - that of the last demultiplex level before it
+    stage_demultiplex_info: Vec<Demultiplexed>,
     report_html: bool,
     report_json: bool,
 }
@@ -496,10 +936,19 @@ impl RunStage1 {
     #[allow(clippy::too_many_lines, clippy::similar_names)]
     fn create_input_threads(self, parsed: &Config) -> Result<RunStage2> {
         let input_config = &parsed.input;
-        let input_files = open_input_files(input_config).context("Error opening input files")?;
+        let is_bam = input_config.format == config::InputFormat::Bam;
+        let input_files = if is_bam {
+            // noodles reads those itself
+            InputFiles::default()
+        } else {
+            open_input_files(input_config, parsed.options.decompression_thread_count)
+                .context("Error opening input files")?
+        };
 
         let block_size = parsed.options.block_size;
         let buffer_size = parsed.options.buffer_size;
+        let input_format = input_config.format;
+        let fasta_fake_quality = input_config.fasta_fake_quality;
         let channel_size = 2;
         let mut threads = Vec::new();
 
@@ -509,16 +958,54 @@ impl RunStage1 {
         let has_read2 = input_files.read2.is_some() || parsed.input.interleaved;
         let has_index1 = input_files.index1.is_some();
         let has_index2 = input_files.index2.is_some();
+        let (raw_tx_tags, raw_rx_tags) = if is_bam && !input_config.bam_import_tags.is_empty() {
+            let (raw_tx_tags, raw_rx_tags) = bounded(channel_size);
+            (Some(raw_tx_tags), Some(raw_rx_tags))
+        } else {
+            (None, None)
+        };
         #[allow(clippy::if_not_else)]
-        let (thread_read1, mut raw_rx_read2, thread_read2) = if !parsed.input.interleaved {
+        let (thread_read1, mut raw_rx_read2, thread_read2) = if is_bam {
+            let (raw_tx_read2, raw_rx_read2) = if has_read2 {
+                let (raw_tx_read2, raw_rx_read2) = bounded(channel_size);
+                (Some(raw_tx_read2), Some(raw_rx_read2))
+            } else {
+                (None, None)
+            };
+            let input_config = input_config.clone();
+            let thread_bam = thread::spawn(move || {
+                parse_bam_and_send(
+                    &input_config,
+                    &raw_tx_read1,
+                    raw_tx_read2.as_ref(),
+                    raw_tx_tags.as_ref(),
+                    block_size,
+                );
+            });
+            (thread_bam, raw_rx_read2, None)
+        } else if !parsed.input.interleaved {
             let thread_read1 = thread::spawn(move || {
-                parse_and_send(input_files.read1, &raw_tx_read1, buffer_size, block_size);
+                parse_and_send(
+                    input_files.read1,
+                    &raw_tx_read1,
+                    buffer_size,
+                    block_size,
+                    input_format,
+                    fasta_fake_quality,
+                );
             });
             let (raw_rx_read2, thread_read2) = match input_files.read2 {
                 Some(reader_read2) => {
                     let (raw_tx_read2, raw_rx_read2) = bounded(channel_size);
                     let thread_read2 = thread::spawn(move || {
-                        parse_and_send(reader_read2, &raw_tx_read2, buffer_size, block_size);
+                        parse_and_send(
+                            reader_read2,
+                            &raw_tx_read2,
+                            buffer_size,
+                            block_size,
+                            input_format,
+                            fasta_fake_quality,
+                        );
                     });
                     (Some(raw_rx_read2), Some(thread_read2))
                 }
@@ -535,6 +1022,8 @@ impl RunStage1 {
                     &raw_tx_read2,
                     buffer_size,
                     block_size,
+                    input_format,
+                    fasta_fake_quality,
                 );
             });
 
@@ -550,7 +1039,14 @@ impl RunStage1 {
             Some(reader_index1) => {
                 let (raw_tx_index1, raw_rx_index1) = bounded(channel_size);
                 let thread_index1 = thread::spawn(move || {
-                    parse_and_send(reader_index1, &raw_tx_index1, buffer_size, block_size);
+                    parse_and_send(
+                        reader_index1,
+                        &raw_tx_index1,
+                        buffer_size,
+                        block_size,
+                        input_format,
+                        fasta_fake_quality,
+                    );
                 });
                 (Some(raw_rx_index1), Some(thread_index1))
             }
@@ -564,7 +1060,14 @@ impl RunStage1 {
             Some(reader_index2) => {
                 let (raw_tx_index2, raw_rx_index2) = bounded(channel_size);
                 let thread_index2 = thread::spawn(move || {
-                    parse_and_send(reader_index2, &raw_tx_index2, buffer_size, block_size);
+                    parse_and_send(
+                        reader_index2,
+                        &raw_tx_index2,
+                        buffer_size,
+                        block_size,
+                        input_format,
+                        fasta_fake_quality,
+                    );
                 });
                 (Some(raw_rx_index2), Some(thread_index2))
             }
@@ -576,6 +1079,8 @@ impl RunStage1 {
 
         let (combiner_output_tx, combiner_output_rx) =
             bounded::<(usize, io::FastQBlocksCombined)>(channel_size);
+        let output_rejected = parsed.output.as_ref().is_some_and(|o| o.output_rejected);
+        let demultiplexing = matches!(self.demultiplex_info, Demultiplexed::Yes(_));
 
         //to.
         let combiner = thread::spawn(move || {
@@ -614,17 +1119,32 @@ impl RunStage1 {
                     None
                 };
 
-                let out = (
-                    block_no,
-                    io::FastQBlocksCombined {
-                        read1: block_read1,
-                        read2: block_read2,
-                        index1: block_index1,
-                        index2: block_index2,
-                        output_tags: None,
-                        tags: None,
-                    },
-                );
+                let block_tags = raw_rx_tags
+                    .as_ref()
+                    .map(|raw_rx_tags| match raw_rx_tags.recv() {
+                        Ok(tags) => tags,
+                        _ => panic!("Block for read1 received, but no imported BAM tags!"),
+                    });
+
+                let mut block = io::FastQBlocksCombined {
+                    read1: block_read1,
+                    read2: block_read2,
+                    index1: block_index1,
+                    index2: block_index2,
+                    output_tags: None,
+                    tags: block_tags,
+                    rejected: None,
+                    singletons: None,
+                };
+                if output_rejected {
+                    let mut rejected = block.empty();
+                    if demultiplexing {
+                        // the barcode the reads had when they were rejected
+                        rejected.output_tags = Some(Vec::new());
+                    }
+                    block.rejected = Some(Box::new(rejected));
+                }
+                let out = (block_no, block);
                 block_no += 1;
                 match combiner_output_tx.send(out) {
                     Ok(()) => {}
@@ -642,7 +1162,7 @@ impl RunStage1 {
             report_html: self.report_html,
             report_json: self.report_json,
             demultiplex_info: self.demultiplex_info.clone(),
-            demultiplex_start: self.demultiplex_start,
+            stage_demultiplex_info: self.stage_demultiplex_info.clone(),
             input_threads: threads,
             combiner_thread: combiner,
             combiner_output_rx,
@@ -656,7 +1176,7 @@ struct RunStage2 {
     report_html: bool,
     report_json: bool,
     demultiplex_info: Demultiplexed,
-    demultiplex_start: usize,
+    stage_demultiplex_info: Vec<Demultiplexed>,
 
     input_threads: Vec<thread::JoinHandle<()>>,
     combiner_thread: thread::JoinHandle<()>,
@@ -691,7 +1211,7 @@ impl RunStage2 {
                 let output_tx2 = channels[stage_no + 1].0.clone();
                 let output_prefix = output_prefix.clone();
                 let output_directory = self.output_directory.clone();
-                let demultiplex_info2 = self.demultiplex_info.clone();
+                let demultiplex_info2 = self.stage_demultiplex_info[stage_no].clone();
                 let report_collector = report_collector.clone();
                 if needs_serial {
                     threads.push(thread::spawn(move || {
@@ -715,10 +1235,8 @@ impl RunStage2 {
                                         let do_continue = handle_stage(
                                             to_output,
                                             &output_tx2,
-                                            stage_no,
                                             &mut stage,
                                             &demultiplex_info2,
-                                            self.demultiplex_start,
                                         );
                                         if !do_continue && transmits_premature_termination {
                                             break 'outer;
@@ -729,16 +1247,12 @@ impl RunStage2 {
                                 }
                             }
                         }
+                        if let Some(block) = stage.flush() {
+                            // downstream might have hung up already, that's fine
+                            let _ = output_tx2.send((last_block_outputted + 1, block));
+                        }
                         let report = stage
-                            .finalize(
-                                &output_prefix,
-                                &output_directory,
-                                if stage_no >= self.demultiplex_start {
-                                    &demultiplex_info2
-                                } else {
-                                    &Demultiplexed::No
-                                },
-                            )
+                            .finalize(&output_prefix, &output_directory, &demultiplex_info2)
                             .unwrap();
                         if let Some(report) = report {
                             report_collector.lock().unwrap().push(report);
@@ -752,10 +1266,8 @@ impl RunStage2 {
                                     handle_stage(
                                         block,
                                         &output_tx2,
-                                        stage_no,
                                         &mut stage,
                                         &demultiplex_info2,
-                                        self.demultiplex_start,
                                     );
                                 }
                                 Err(_) => {
@@ -827,6 +1339,10 @@ impl RunStage3 {
         let interleaved = parsed.output.as_ref().is_some_and(|o| o.interleave);
         let output_buffer_size = parsed.options.output_buffer_size;
         let cloned_input_config = parsed.input.clone();
+        let compression_level = parsed
+            .output
+            .as_ref()
+            .and_then(config::Output::effective_compression_level);
 
         let mut output_files = open_output_files(
             parsed,
@@ -838,6 +1354,10 @@ impl RunStage3 {
 
         let output_directory = self.output_directory;
         let demultiplex_info = self.demultiplex_info;
+        let rejected_before_demultiplex_tag = match demultiplex_info {
+            Demultiplexed::No => None,
+            Demultiplexed::Yes(_) => Some(REJECTED_BEFORE_DEMULTIPLEX),
+        };
         let report_collector = self.report_collector.clone();
 
         let output = thread::spawn(move || {
@@ -862,7 +1382,53 @@ impl RunStage3 {
                             interleaved,
                             &demultiplex_info,
                             output_buffer_size,
-                        );
+                        )
+                        .expect("Error writing output files");
+                        if let (Some(rejected), Some(rejected_files)) = (
+                            to_output.1.rejected.as_ref(),
+                            output_files.output_rejected.as_mut(),
+                        ) {
+                            // reads rejected after the (last) Demultiplex step
+                            // are split like the main output, the others have no (final) barcode
+                            output_block_demultiplex(
+                                rejected,
+                                rejected_files,
+                                interleaved,
+                                rejected_before_demultiplex_tag,
+                                output_buffer_size,
+                            )
+                            .expect("Error writing rejected output files");
+                            if !output_files.output_rejected_demultiplexed.is_empty() {
+                                output_block(
+                                    rejected,
+                                    &mut output_files.output_rejected_demultiplexed,
+                                    interleaved,
+                                    &demultiplex_info,
+                                    output_buffer_size,
+                                )
+                                .expect("Error writing rejected output files");
+                            }
+                            // the failing mates of pairs split by EmitOrphans
+                            if let Some(rejected_singletons) = rejected.singletons.as_ref() {
+                                output_singletons(
+                                    rejected_singletons,
+                                    rejected_files,
+                                    interleaved,
+                                    output_buffer_size,
+                                );
+                            }
+                        }
+                        if let (Some(singletons), Some(singleton_files)) = (
+                            to_output.1.singletons.as_ref(),
+                            output_files.output_singletons.as_mut(),
+                        ) {
+                            output_singletons(
+                                singletons,
+                                singleton_files,
+                                interleaved,
+                                output_buffer_size,
+                            );
+                        }
                     } else {
                         break;
                     }
@@ -877,7 +1443,13 @@ impl RunStage3 {
                 "Error in stage threads occured: {stage_errors:?}"
             );
 
-            for set_of_output_files in &mut output_files.output_fastq {
+            for set_of_output_files in output_files
+                .output_fastq
+                .iter_mut()
+                .chain(output_files.output_rejected.as_mut())
+                .chain(output_files.output_rejected_demultiplexed.iter_mut())
+                .chain(output_files.output_singletons.as_mut())
+            {
                 set_of_output_files
                     .lock()
                     .unwrap()
@@ -898,6 +1470,7 @@ impl RunStage3 {
                             &output_directory.to_string_lossy(),
                             &cloned_input_config,
                             &raw_config,
+                            compression_level,
                         )
                         .expect("error writing json report"),
                     )
@@ -997,25 +1570,30 @@ pub fn run(
 fn handle_stage(
     block: (usize, io::FastQBlocksCombined),
     output_tx2: &crossbeam::channel::Sender<(usize, io::FastQBlocksCombined)>,
-    stage_no: usize,
     stage: &mut Transformation,
     demultiplex_info: &Demultiplexed,
-    demultiplex_start: usize,
 ) -> bool {
     let mut out_block = block.1;
     let mut do_continue = true;
     let stage_continue;
+    let rejected_before = out_block
+        .rejected
+        .as_ref()
+        .map_or([0; 3], |r| r.len_including_singletons());
 
-    (out_block, stage_continue) = stage.apply(
-        out_block,
-        block.0,
-        if stage_no >= demultiplex_start {
-            demultiplex_info
-        } else {
-            &Demultiplexed::No
-        },
-    );
+    (out_block, stage_continue) = stage.apply(out_block, block.0, demultiplex_info);
     do_continue = do_continue && stage_continue;
+    // only name the step if it rejected anything
+    if let Some(rejected) = out_block
+        .rejected
+        .as_mut()
+        .filter(|rejected| rejected.len_including_singletons() != rejected_before)
+    {
+        rejected.append_comment_to_owned_names(
+            rejected_before,
+            format!("rejected_by={stage}").as_bytes(),
+        );
+    }
 
     match output_tx2.send((block.0, out_block)) {
         Ok(()) => {}
@@ -1041,19 +1619,20 @@ fn output_block(
     interleaved: bool,
     demultiplexed: &Demultiplexed,
     buffer_size: usize,
-) {
+) -> Result<()> {
     block.sanity_check();
     match demultiplexed {
         Demultiplexed::No => {
-            output_block_demultiplex(block, &mut output_files[0], interleaved, None, buffer_size);
+            output_block_demultiplex(block, &mut output_files[0], interleaved, None, buffer_size)?;
         }
         Demultiplexed::Yes(demultiplex_info) => {
             for (file_no, (tag, _output_key)) in demultiplex_info.iter_outputs().enumerate() {
                 let output_files = &mut output_files[file_no];
-                output_block_demultiplex(block, output_files, interleaved, Some(tag), buffer_size);
+                output_block_demultiplex(block, output_files, interleaved, Some(tag), buffer_size)?;
             }
         }
     }
+    Ok(())
 }
 
 #[allow(clippy::if_not_else)]
@@ -1063,9 +1642,12 @@ fn output_block_demultiplex(
     interleaved: bool,
     tag: Option<u16>,
     buffer_size: usize,
-) {
+) -> Result<()> {
     let mut buffer = Vec::with_capacity(buffer_size);
     let mut of = output_files.lock().unwrap();
+    if let Some(bam) = of.bam.as_mut() {
+        return bam.write_block(block, tag);
+    }
     if !interleaved {
         output_block_inner(
             of.read1.as_mut(),
@@ -1110,6 +1692,40 @@ fn output_block_demultiplex(
         tag,
         block.output_tags.as_ref(),
     );
+    Ok(())
+}
+
+/// Singletons have no mates, so with interleaved output,
+/// the read2 singletons follow the read1 ones in the same file.
+fn output_singletons(
+    singletons: &io::Singletons,
+    output_files: &mut Arc<Mutex<OutputFastqs>>,
+    interleaved: bool,
+    buffer_size: usize,
+) {
+    let mut buffer = Vec::with_capacity(buffer_size);
+    let mut of = output_files.lock().unwrap();
+    output_block_inner(
+        of.read1.as_mut(),
+        Some(&singletons.read1),
+        &mut buffer,
+        buffer_size,
+        None,
+        None,
+    );
+    let read2_output = if interleaved {
+        of.read1.as_mut()
+    } else {
+        of.read2.as_mut()
+    };
+    output_block_inner(
+        read2_output,
+        Some(&singletons.read2),
+        &mut buffer,
+        buffer_size,
+        None,
+        None,
+    );
 }
 
 fn output_block_inner<'a>(
@@ -1129,7 +1745,7 @@ fn output_block_inner<'a>(
             block.unwrap().get_pseudo_iter()
         };
         while let Some(read) = pseudo_iter.pseudo_next() {
-            read.append_as_fastq(buffer);
+            of.read_format.append(&read, buffer);
             if buffer.len() > buffer_size {
                 of.writer.write_all(buffer).unwrap();
                 if let Some(ref mut hasher) = of.hasher {
@@ -1171,8 +1787,8 @@ fn output_block_interleaved<'a>(
             let read2 = pseudo_iter_2
                 .pseudo_next()
                 .expect("Uneven number of r1 and r2 in interleaved output. Bug?");
-            read.append_as_fastq(buffer);
-            read2.append_as_fastq(buffer);
+            of.read_format.append(&read, buffer);
+            of.read_format.append(&read2, buffer);
             if buffer.len() > buffer_size {
                 of.writer.write_all(buffer).unwrap();
                 if let Some(ref mut hasher) = of.hasher {
@@ -1204,6 +1820,7 @@ fn output_json_report(
     current_dir: &str,
     input_config: &crate::config::Input,
     raw_config: &str,
+    compression_level: Option<u8>,
 ) -> Result<String> {
     use json_value_merge::Merge;
     let mut output: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
@@ -1241,6 +1858,12 @@ fn output_json_report(
         "working_directory".to_string(),
         serde_json::Value::String(current_dir.to_string()),
     );
+    if let Some(compression_level) = compression_level {
+        run_info.insert(
+            "compression_level".to_string(),
+            serde_json::Value::from(compression_level),
+        );
+    }
 
     output.insert("run_info".to_string(), serde_json::Value::Object(run_info));
 
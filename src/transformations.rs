@@ -9,7 +9,7 @@ use anyhow::{bail, Result};
 use serde_valid::Validate;
 
 use crate::{
-    config::{RegionDefinition, Target, TargetPlusAll},
+    config::{PairedEndPolicy, RegionDefinition, Target, TargetPlusAll},
     demultiplex::{DemultiplexInfo, Demultiplexed},
     dna::HitRegion,
     io,
@@ -136,6 +136,16 @@ pub trait Step {
     ) -> Result<Option<FinalizeReportResult>> {
         Ok(None)
     }
+
+    /// Steps that add their own section to the report: its label.
+    /// `expand` then assigns them a report number with `set_report_no`,
+    /// which they return from `finalize`.
+    fn report_label(&self) -> Option<String> {
+        None
+    }
+
+    fn set_report_no(&mut self, _report_no: usize) {}
+
     fn apply(
         &mut self,
         block: crate::io::FastQBlocksCombined,
@@ -149,6 +159,12 @@ pub trait Step {
         false
     }
 
+    /// Serial steps that hold back reads in `apply` (returning empty blocks instead)
+    /// hand them on here once the input has ended.
+    fn flush(&mut self) -> Option<crate::io::FastQBlocksCombined> {
+        None
+    }
+
     /// When we have a transformation that says 'Enough reads'
     /// like Head, that sends the 'end transmission' signal
     /// upstream by closing it's receiver.
@@ -160,6 +176,16 @@ pub trait Step {
     fn transmits_premature_termination(&self) -> bool {
         true
     }
+
+    /// The per-read filters: how are read pairs filtered (if set)?
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        None
+    }
+
+    /// Does this filter send surviving mates to the _singletons output?
+    fn emits_orphans(&self) -> bool {
+        self.paired_end() == Some(PairedEndPolicy::EmitOrphans)
+    }
 }
 
 /// A transformation that delays processing
@@ -214,6 +240,12 @@ impl Step for Box<_InternalReadCount> {
     fn needs_serial(&self) -> bool {
         true
     }
+    fn report_label(&self) -> Option<String> {
+        Some(self.label.clone())
+    }
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
     fn transmits_premature_termination(&self) -> bool {
         true // That's the magic as opposed to the usual reports
     }
@@ -301,9 +333,16 @@ pub enum Transformation {
     ReverseComplement(edits::ReverseComplement),
     Rename(edits::Rename),
     TrimAdapterMismatchTail(edits::TrimAdapterMismatchTail),
+    TrimAdapterAutoDetect(edits::TrimAdapterAutoDetect),
+    TrimAdapters(edits::TrimAdapters),
+    TrimAdapterByOverlap(edits::TrimAdapterByOverlap),
+    MergeReads(edits::MergeReads),
+    CorrectByOverlap(edits::CorrectByOverlap),
+    TrimAmpliconPrimers(edits::TrimAmpliconPrimers),
     TrimPolyTail(edits::TrimPolyTail),
     TrimQualityStart(edits::TrimQualityStart),
     TrimQualityEnd(edits::TrimQualityEnd),
+    TrimQualityMott(edits::TrimQualityMott),
     SwapR1AndR2(edits::SwapR1AndR2),
 
     Head(filters::Head),
@@ -312,6 +351,7 @@ pub enum Transformation {
     FilterMinLen(filters::MinLen),
     FilterMaxLen(filters::MaxLen),
     FilterMeanQuality(filters::MeanQuality),
+    FilterMaxExpectedErrors(filters::MaxExpectedErrors),
     FilterQualifiedBases(filters::QualifiedBases),
     FilterTooManyN(filters::TooManyN),
     FilterSample(filters::Sample),
@@ -319,6 +359,7 @@ pub enum Transformation {
     FilterLowComplexity(filters::LowComplexity),
     FilterOtherFileByName(filters::OtherFileByName),
     FilterOtherFileBySequence(filters::OtherFileBySequence),
+    FilterKmerContamination(filters::KmerContamination),
     ValidateSeq(validation::ValidateSeq),
     ValidatePhred(validation::ValidatePhred),
     //TODO: validateName that they match in paried end
@@ -330,6 +371,7 @@ pub enum Transformation {
     ExtractRegions(tag::ExtractRegions),
     ExtractAnchor(tag::ExtractAnchor),
     ExtractLength(tag::ExtractLength),
+    ExtractExpectedErrors(tag::ExtractExpectedErrors),
     //edit
     LowercaseTag(tag::LowercaseTag),
     TrimAtTag(tag::TrimAtTag),
@@ -376,7 +418,7 @@ pub(crate) fn validate_target(target: Target, input_def: &crate::config::Input)
     match target {
         Target::Read1 => {}
         Target::Read2 => {
-            if input_def.read2.is_none() {
+            if !input_def.has_read2() {
                 bail!("Read2 is not defined in the input section, but used by transformation");
             }
         }
@@ -394,6 +436,22 @@ pub(crate) fn validate_target(target: Target, input_def: &crate::config::Input)
     Ok(())
 }
 
+/// `max_error_rate` (indel tolerant search) replaces `max_mismatches`.
+pub(crate) fn validate_max_error_rate(
+    max_error_rate: Option<f32>,
+    has_max_mismatches: bool,
+) -> Result<()> {
+    if let Some(max_error_rate) = max_error_rate {
+        if has_max_mismatches {
+            bail!("Set either max_mismatches or max_error_rate, not both.");
+        }
+        if !(0.0..1.0).contains(&max_error_rate) {
+            bail!("max_error_rate must be in [0.0, 1.0).");
+        }
+    }
+    Ok(())
+}
+
 pub(crate) fn validate_dna(dna: &[u8]) -> Result<()> {
     for &base in dna {
         if !matches!(base, b'A' | b'T' | b'C' | b'G') {
@@ -422,7 +480,12 @@ impl Transformation {
         let mut res = Vec::new();
         let mut res_report_labels = Vec::new();
         let mut report_no = 0;
-        for transformation in transforms {
+        for mut transformation in transforms {
+            if let Some(report_label) = transformation.report_label() {
+                transformation.set_report_no(report_no);
+                res_report_labels.push(report_label);
+                report_no += 1;
+            }
             match transformation {
                 Transformation::Report(config) => {
                     res_report_labels.push(config.label);
@@ -492,12 +555,21 @@ impl Transformation {
                     res.push(Transformation::_ReportPart1(Box::new(part1)));
                     res.push(Transformation::_ReportPart2(Box::new(part2))) */
                 }
-                Transformation::_InternalReadCount(config) => {
-                    let mut config: Box<_> = config.clone();
-                    config.report_no = report_no;
-                    res_report_labels.push(config.label.clone());
-                    report_no += 1;
-                    res.push(Transformation::_InternalReadCount(config));
+                Transformation::Demultiplex(mut config) => {
+                    // the per region mismatch limits need the tag's layout
+                    config.region_lengths = res
+                        .iter()
+                        .rev()
+                        .find_map(|t| match t {
+                            Transformation::ExtractRegions(extract)
+                                if extract.label == config.label =>
+                            {
+                                Some(extract.regions.iter().map(|x| x.length).collect())
+                            }
+                            _ => None,
+                        })
+                        .unwrap_or_default();
+                    res.push(Transformation::Demultiplex(config));
                 }
                 Transformation::ExtractRegion(config) => {
                     let regions = vec![RegionDefinition {
@@ -614,7 +686,76 @@ fn apply_filter(
     apply_bool_filter(block, keep);
 }
 
+/// The per-read filters take either a target, or a paired end policy
+/// that filters Read1 and Read2.
+pub(crate) fn validate_read_filter_target(
+    target: Option<Target>,
+    paired_end: Option<PairedEndPolicy>,
+    input_def: &crate::config::Input,
+) -> Result<()> {
+    match (target, paired_end) {
+        (Some(target), None) => validate_target(target, input_def),
+        (None, Some(_)) => {
+            if !input_def.has_read2() {
+                bail!("paired_end requires Read2 in the input section (or interleaved input)");
+            }
+            Ok(())
+        }
+        (Some(_), Some(_)) => {
+            bail!("Set either target or paired_end, not both. paired_end always filters Read1 and Read2")
+        }
+        (None, None) => {
+            bail!("Missing target. Set target = 'Read1'|'Read2'|'Index1'|'Index2' or paired_end = 'RemovePair'|'KeepPairIfAnyPasses'|'EmitOrphans'")
+        }
+    }
+}
+
+fn apply_read_filter(
+    target: Option<Target>,
+    paired_end: Option<PairedEndPolicy>,
+    block: &mut io::FastQBlocksCombined,
+    mut f: impl FnMut(&mut io::WrappedFastQRead) -> bool,
+) {
+    let Some(paired_end) = paired_end else {
+        apply_filter(target.expect("target or paired_end must be set"), block, f);
+        return;
+    };
+    let passed_read1 = block.read1.apply(&mut f);
+    let passed_read2 = block
+        .read2
+        .as_ref()
+        .expect("Input def and transformation def mismatch")
+        .apply(&mut f);
+    let keep: Vec<bool> = passed_read1
+        .iter()
+        .zip(passed_read2.iter())
+        .map(|(a, b)| match paired_end {
+            PairedEndPolicy::RemovePair | PairedEndPolicy::EmitOrphans => *a && *b,
+            PairedEndPolicy::KeepPairIfAnyPasses => *a || *b,
+        })
+        .collect();
+    if paired_end == PairedEndPolicy::EmitOrphans {
+        // only pairs where both mates failed are rejected as a whole,
+        // the failing mate of split pairs is stored by store_singletons
+        let any_passed: Vec<bool> = passed_read1
+            .iter()
+            .zip(passed_read2.iter())
+            .map(|(a, b)| *a || *b)
+            .collect();
+        block.store_rejected(&any_passed);
+        block.store_singletons(&passed_read1, &passed_read2);
+        retain_molecules(block, &keep);
+    } else {
+        apply_bool_filter(block, keep);
+    }
+}
+
 fn apply_bool_filter(block: &mut io::FastQBlocksCombined, keep: Vec<bool>) {
+    block.store_rejected(&keep);
+    retain_molecules(block, &keep);
+}
+
+fn retain_molecules(block: &mut io::FastQBlocksCombined, keep: &[bool]) {
     let mut iter = keep.iter();
     block.read1.entries.retain(|_| *iter.next().unwrap());
     if let Some(ref mut read2) = block.read2 {
@@ -635,6 +776,13 @@ fn apply_bool_filter(block: &mut io::FastQBlocksCombined, keep: Vec<bool>) {
             tag_entries.retain(|_| *iter.next().unwrap());
         }
     }
+    if let Some(output_tags) = block.output_tags.as_mut() {
+        let mut iter = keep.iter();
+        output_tags.retain(|_| *iter.next().unwrap());
+    }
+    if let Some(singletons) = block.singletons.as_mut() {
+        singletons.molecules_retained(keep);
+    }
 }
 
 fn apply_filter_all(
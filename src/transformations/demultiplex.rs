@@ -1,23 +1,242 @@
-use anyhow::{Result, bail};
-use std::collections::BTreeMap;
+use anyhow::{Context, Result, bail};
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 
-use super::{InputInfo, Step, Transformation};
+use super::{FinalizeReportResult, InputInfo, Step, Transformation};
 use crate::config::deser::btreemap_dna_string_from_string;
-use crate::demultiplex::{DemultiplexInfo, Demultiplexed};
+use crate::demultiplex::{DemultiplexInfo, Demultiplexed, REJECTED_BEFORE_DEMULTIPLEX};
 use serde_valid::Validate;
 
 #[derive(serde::Deserialize, Debug, Validate, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Demultiplex {
     pub label: String,
+    #[serde(default)]
     pub max_hamming_distance: u8,
+    /// mismatches allowed in each region of the (`ExtractRegions`) tag,
+    /// e.g. [1, 1] for index1 + index2. Replaces `max_hamming_distance`
+    #[serde(default)]
+    pub max_hamming_distance_per_region: Vec<u8>,
     pub output_unmatched: bool,
     // a mapping barcode -> output infix
-    #[serde(deserialize_with = "btreemap_dna_string_from_string")]
+    #[serde(default, deserialize_with = "btreemap_dna_string_from_string")]
     pub barcode_to_name: BTreeMap<Vec<u8>, String>,
+    /// alternatively: read barcode -> name from a TSV/CSV/Illumina sample sheet
+    pub sample_sheet: Option<String>,
+    /// key of the demultiplexing statistics in the json report (if any)
+    pub report_label: Option<String>,
+    /// how many of the most frequent unmatched barcodes to report
+    #[serde(default = "default_report_top_unknown_barcodes")]
+    pub report_top_unknown_barcodes: usize,
+    #[serde(skip)]
+    pub report_no: usize,
     #[serde(skip)]
     pub had_iupac: bool,
+    /// barcode -> barcode index (1.., in `barcode_to_name` order)
+    #[serde(skip)]
+    pub barcode_to_index: HashMap<Vec<u8>, u16>,
+    /// [previous level tag][barcode index] -> output tag
+    #[serde(skip)]
+    pub tag_lookup: Vec<Vec<u16>>,
+    /// barcode index for reads matching more than one barcode.
+    /// Only set if the barcodes are close enough for that to happen.
+    #[serde(skip)]
+    pub ambiguous_index: Option<u16>,
+    /// barcode pairs less than 2*max_hamming_distance+1 mismatches apart, for the report
+    #[serde(skip)]
+    pub colliding_barcodes: Vec<(Vec<u8>, Vec<u8>)>,
+    /// lengths of the tag's regions, if it was extracted by `ExtractRegions`.
+    /// Set when expanding the transformations.
+    #[serde(skip)]
+    pub region_lengths: Vec<usize>,
+    /// (start, end) of the tag's regions. One region if the layout is unknown
+    #[serde(skip)]
+    pub region_ranges: Vec<(usize, usize)>,
+    /// (start, end) -> allowed mismatches
+    #[serde(skip)]
+    pub region_limits: Vec<((usize, usize), u8)>,
+    #[serde(skip)]
+    pub stats: DemultiplexStats,
+}
+
+fn default_report_top_unknown_barcodes() -> usize {
+    10
+}
+
+/// Counts for the demultiplexing report
+#[derive(Debug, Clone, Default)]
+pub struct DemultiplexStats {
+    /// by barcode index
+    perfect: Vec<usize>,
+    /// by barcode index
+    corrected: Vec<usize>,
+    /// assigned reads by number of mismatches to their barcode
+    mismatch_histogram: Vec<usize>,
+    reads_with_mismatches_per_region: Vec<usize>,
+    ambiguous: usize,
+    unmatched: usize,
+    unknown_barcodes: HashMap<Vec<u8>, usize>,
+}
+
+impl Demultiplex {
+    /// 0 = no barcode matched.
+    /// Also returns the barcode if it was matched with mismatches.
+    fn barcode_index(&self, key: &[u8]) -> (u16, Option<&[u8]>) {
+        if let Some(index) = self.barcode_to_index.get(key) {
+            return (*index, None);
+        }
+        if !self.had_iupac && self.region_limits.iter().all(|(_, limit)| *limit == 0) {
+            return (0, None);
+        }
+        let mut res: Option<(u16, &Vec<u8>, &String)> = None;
+        for (barcode, name, index) in self.iter_barcodes() {
+            if self.within_limits(barcode, key) {
+                match res {
+                    None => res = Some((index, barcode, name)),
+                    // barcodes sharing an output are not ambiguous
+                    Some((_, _, res_name)) if res_name == name => {}
+                    Some((first_index, first_barcode, _)) => {
+                        return match self.ambiguous_index {
+                            Some(ambiguous_index) => (ambiguous_index, None),
+                            None => (first_index, Some(first_barcode)),
+                        };
+                    }
+                }
+            }
+        }
+        res.map_or((0, None), |(index, barcode, _)| (index, Some(barcode)))
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn distance(&self, barcode: &[u8], key: &[u8]) -> usize {
+        //barcodes typically are below teh distance where we would consider
+        //SIMD to be helpful. Could benchmark though
+        if self.had_iupac {
+            crate::dna::iupac_hamming_distance(barcode, key)
+        } else {
+            bio::alignment::distance::hamming(barcode, key) as usize
+        }
+    }
+
+    fn within_limits(&self, barcode: &[u8], key: &[u8]) -> bool {
+        barcode.len() == key.len()
+            && self.region_limits.iter().all(|((start, end), limit)| {
+                let end = (*end).min(key.len());
+                self.distance(&barcode[*start..end], &key[*start..end]) <= usize::from(*limit)
+            })
+    }
+
+    /// Pairs of barcodes (with distinct outputs) that a read could match both of
+    /// within the allowed mismatches.
+    fn find_colliding_barcodes(&self) -> Vec<(&[u8], &[u8])> {
+        let mut res = Vec::new();
+        for (ii, (a, a_name)) in self.barcode_to_name.iter().enumerate() {
+            for (b, b_name) in self.barcode_to_name.iter().skip(ii + 1) {
+                if a_name != b_name
+                    && a.len() == b.len()
+                    && self.region_limits.iter().all(|((start, end), limit)| {
+                        let end = (*end).min(a.len());
+                        a[*start..end]
+                            .iter()
+                            .zip(b[*start..end].iter())
+                            .filter(|(x, y)| !crate::dna::iupac_compatible(**x, **y))
+                            .count()
+                            <= 2 * usize::from(*limit)
+                    })
+                {
+                    res.push((a.as_slice(), b.as_slice()));
+                }
+            }
+        }
+        res
+    }
+
+    /// Barcodes for the report. Regions are separated by '+'
+    fn display_barcode(&self, barcode: &[u8]) -> String {
+        if self.region_ranges.len() > 1 && barcode.len() == self.region_lengths.iter().sum() {
+            self.region_ranges
+                .iter()
+                .map(|(start, end)| String::from_utf8_lossy(&barcode[*start..*end]))
+                .collect::<Vec<_>>()
+                .join("+")
+        } else {
+            String::from_utf8_lossy(barcode).into_owned()
+        }
+    }
+
+    /// Tally a read for the report
+    fn count(
+        &self,
+        stats: &mut DemultiplexStats,
+        key: Vec<u8>,
+        barcode_index: u16,
+        corrected_barcode: Option<&[u8]>,
+    ) {
+        if barcode_index == 0 {
+            stats.unmatched += 1;
+            *stats.unknown_barcodes.entry(key).or_insert(0) += 1;
+        } else if Some(barcode_index) == self.ambiguous_index {
+            stats.ambiguous += 1;
+        } else {
+            let mismatches = match corrected_barcode {
+                None => {
+                    stats.perfect[barcode_index as usize] += 1;
+                    0
+                }
+                Some(barcode) => {
+                    stats.corrected[barcode_index as usize] += 1;
+                    for (count, (start, end)) in stats
+                        .reads_with_mismatches_per_region
+                        .iter_mut()
+                        .zip(self.region_ranges.iter())
+                    {
+                        let end = (*end).min(key.len());
+                        if self.distance(&barcode[*start..end], &key[*start..end]) > 0 {
+                            *count += 1;
+                        }
+                    }
+                    self.distance(barcode, &key)
+                }
+            };
+            if stats.mismatch_histogram.len() <= mismatches {
+                stats.mismatch_histogram.resize(mismatches + 1, 0);
+            }
+            stats.mismatch_histogram[mismatches] += 1;
+        }
+    }
+
+    /// Checks on `barcode_to_name` - in validate, or, for sample sheets
+    /// (which are only read in init), there.
+    fn check_barcodes(&self, region_len: Option<usize>) -> Result<()> {
+        if self.barcode_to_name.is_empty() {
+            bail!("Demultiplex: no barcodes defined. Set barcode_to_name or sample_sheet.");
+        }
+        if self.barcode_to_name.len() > 2_usize.pow(16) - 1 {
+            bail!("Too many barcodes. Can demultiplex at most 2^16-1 barcodes");
+        }
+        if let Some(region_len) = region_len {
+            for barcode in self.barcode_to_name.keys() {
+                if barcode.len() != region_len {
+                    bail!(
+                        "Barcode length {} doesn't match sum of region lengths ({region_len}) of tag '{}'. Offending barcode: (separators ommited): {}",
+                        barcode.len(),
+                        self.label,
+                        String::from_utf8_lossy(barcode)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// (barcode, output name, barcode index) in `barcode_to_name` order
+    #[allow(clippy::cast_possible_truncation)]
+    fn iter_barcodes(&self) -> impl Iterator<Item = (&Vec<u8>, &String, u16)> {
+        self.barcode_to_name
+            .iter()
+            .enumerate()
+            .map(|(ii, (barcode, name))| (barcode, name, (ii + 1) as u16))
+    }
 }
 
 impl Step for Demultiplex {
@@ -31,29 +250,48 @@ impl Step for Demultiplex {
         _output_def: Option<&crate::config::Output>,
         all_transforms: &[Transformation],
     ) -> Result<()> {
-        if self.barcode_to_name.len() > 2_usize.pow(16) - 1 {
-            bail!("Too many barcodes. Can demultiplex at most 2^16-1 barcodes");
+        if self.sample_sheet.is_some() && !self.barcode_to_name.is_empty() {
+            bail!("Demultiplex: use either barcode_to_name or sample_sheet, not both.");
         }
-        /* let region_len: usize = self.regions.iter().map(|x| x.length).sum::<usize>();
-        for barcode in self.barcode_to_name.keys() {
-            if barcode.len() != region_len {
-                bail!(
-                    "Barcode length {} doesn't match sum of region lengths ({region_len}). Offending barcode: (separators ommited): {}",
-                    barcode.len(),
-                    std::str::from_utf8(barcode).unwrap()
-                );
+        if !self.max_hamming_distance_per_region.is_empty() && self.max_hamming_distance > 0 {
+            bail!(
+                "Demultiplex: use either max_hamming_distance or max_hamming_distance_per_region, not both."
+            );
+        }
+        // the length of tags from other Extract* steps is not known up front.
+        let region_len: Option<usize> = all_transforms.iter().find_map(|t| match t {
+            Transformation::ExtractRegion(config) if config.label == self.label => Some(config.len),
+            Transformation::ExtractRegions(config) if config.label == self.label => {
+                Some(config.regions.iter().map(|x| x.length).sum())
+            }
+            _ => None,
+        });
+        if !self.max_hamming_distance_per_region.is_empty() {
+            let region_count = all_transforms.iter().find_map(|t| match t {
+                Transformation::ExtractRegion(config) if config.label == self.label => Some(1),
+                Transformation::ExtractRegions(config) if config.label == self.label => {
+                    Some(config.regions.len())
+                }
+                _ => None,
+            });
+            match region_count {
+                None => bail!(
+                    "Demultiplex: max_hamming_distance_per_region requires the tag '{}' to be extracted by ExtractRegion(s).",
+                    self.label
+                ),
+                Some(region_count) if region_count != self.max_hamming_distance_per_region.len() => {
+                    bail!(
+                        "Demultiplex: max_hamming_distance_per_region has {} entries, but tag '{}' has {region_count} region(s).",
+                        self.max_hamming_distance_per_region.len(),
+                        self.label
+                    )
+                }
+                Some(_) => {}
             }
-        } */
-        // yes, we do this multiple times.
-        // Not worth caching the result
-        let demultiplex_count = all_transforms
-            .iter()
-            .filter(|t| matches!(t, Transformation::Demultiplex(_)))
-            .count();
-        if demultiplex_count > 1 {
-            bail!("Only one level of demultiplexing is supported.");
         }
-
+        if self.sample_sheet.is_none() {
+            self.check_barcodes(region_len)?;
+        }
         Ok(())
     }
 
@@ -62,23 +300,85 @@ impl Step for Demultiplex {
         _input_info: &InputInfo,
         _output_prefix: &str,
         _output_directory: &Path,
-        _demultiplex_info: &Demultiplexed,
+        demultiplex_info: &Demultiplexed,
     ) -> Result<Option<DemultiplexInfo>> {
+        if let Some(sample_sheet) = &self.sample_sheet {
+            self.barcode_to_name = read_sample_sheet(sample_sheet)?;
+            // ExtractRegion(s) tags have their layout set when expanding the transformations
+            let region_len =
+                (!self.region_lengths.is_empty()).then(|| self.region_lengths.iter().sum());
+            self.check_barcodes(region_len)?;
+        }
         self.had_iupac = self
             .barcode_to_name
             .keys()
             .any(|x| crate::dna::contains_iupac_ambigous(x));
-        Ok(Some(DemultiplexInfo::new(
+        self.barcode_to_index = self
+            .iter_barcodes()
+            .map(|(barcode, _name, index)| (barcode.clone(), index))
+            .collect();
+        self.region_ranges = if self.region_lengths.is_empty() {
+            vec![(0, usize::MAX)]
+        } else {
+            let mut start = 0;
+            self.region_lengths
+                .iter()
+                .map(|len| {
+                    start += len;
+                    (start - len, start)
+                })
+                .collect()
+        };
+        self.region_limits = if self.max_hamming_distance_per_region.is_empty() {
+            vec![((0, usize::MAX), self.max_hamming_distance)]
+        } else {
+            self.region_ranges
+                .iter()
+                .copied()
+                .zip(self.max_hamming_distance_per_region.iter().copied())
+                .collect()
+        };
+        self.stats = DemultiplexStats {
+            perfect: vec![0; self.barcode_to_name.len() + 1],
+            corrected: vec![0; self.barcode_to_name.len() + 1],
+            reads_with_mismatches_per_region: vec![0; self.region_ranges.len()],
+            ..Default::default()
+        };
+        self.colliding_barcodes = self
+            .find_colliding_barcodes()
+            .into_iter()
+            .map(|(a, b)| (a.to_vec(), b.to_vec()))
+            .collect();
+        if !self.colliding_barcodes.is_empty() {
+            self.ambiguous_index = Some(
+                u16::try_from(self.barcode_to_name.len() + 1)
+                    .expect("barcode count checked in validation"),
+            );
+        }
+        let previous = match demultiplex_info {
+            Demultiplexed::No => None,
+            Demultiplexed::Yes(info) => Some(info),
+        };
+        let (info, tag_lookup) = DemultiplexInfo::new_level(
+            previous,
             &self.barcode_to_name,
             self.output_unmatched,
-        )?))
+            self.ambiguous_index.is_some(),
+        )?;
+        self.tag_lookup = tag_lookup;
+        Ok(Some(info))
+    }
+
+    fn needs_serial(&self) -> bool {
+        // for the report
+        self.report_label.is_some()
     }
 
     fn apply(
         &mut self,
         mut block: crate::io::FastQBlocksCombined,
         _block_no: usize,
-        demultiplex_info: &Demultiplexed,
+        _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
         let hits = block
             .tags
@@ -86,46 +386,229 @@ impl Step for Demultiplex {
             .expect("No hits? bug")
             .get(&self.label)
             .expect("Label not present. Should have been caught in validation");
-        let mut tags: Vec<u16> = vec![0; block.len()];
-        let demultiplex_info = demultiplex_info.unwrap();
+        // previous demultiplex levels have already assigned a tag
+        let mut tags: Vec<u16> = block
+            .output_tags
+            .take()
+            .unwrap_or_else(|| vec![0; block.len()]);
+        let collect_stats = self.report_label.is_some();
+        let mut stats = std::mem::take(&mut self.stats);
         for (ii, target_tag) in tags.iter_mut().enumerate() {
             //TODO: We need to refactor this to use our Extract*
             let key = hits[ii]
                 .as_ref()
-                .map(|x| x.joined_sequence(Some(b"-")))
+                .map(|x| x.joined_sequence(None))
                 .unwrap_or_default();
-            let entry = demultiplex_info.barcode_to_tag(&key);
-            match entry {
-                Some(tag) => {
-                    *target_tag = tag;
-                }
-                None => {
-                    if self.had_iupac {
-                        for (barcode, tag) in demultiplex_info.iter_barcodes() {
-                            let distance = crate::dna::iupac_hamming_distance(barcode, &key);
-                            if distance.try_into().unwrap_or(255u8) <= self.max_hamming_distance {
-                                *target_tag = tag;
-                                break;
-                            }
-                        }
-                    }
-                    if self.max_hamming_distance > 0 {
-                        for (barcode, tag) in demultiplex_info.iter_barcodes() {
-                            //barcodes typically are below teh distance where we would consider
-                            //SIMD to be helpful. Could benchmark though
-                            let distance = bio::alignment::distance::hamming(barcode, &key);
-                            if distance.try_into().unwrap_or(255u8) <= self.max_hamming_distance {
-                                *target_tag = tag;
-                                break;
-                            }
-                        }
-                    }
-                    //tag[ii] = 0 -> not found
-                    //todo: hamming distance trial
-                }
+            let (barcode_index, corrected_barcode) = self.barcode_index(&key);
+            *target_tag = self.tag_lookup[*target_tag as usize][barcode_index as usize];
+            if collect_stats {
+                self.count(&mut stats, key, barcode_index, corrected_barcode);
             }
         }
+        self.stats = stats;
         block.output_tags = Some(tags);
+        // reads rejected so far carry a tag of the previous level (if any)
+        if let Some(rejected_tags) = block
+            .rejected
+            .as_mut()
+            .and_then(|rejected| rejected.output_tags.as_mut())
+        {
+            rejected_tags.fill(REJECTED_BEFORE_DEMULTIPLEX);
+        }
         (block, true)
     }
+
+    fn report_label(&self) -> Option<String> {
+        self.report_label.clone()
+    }
+
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
+
+    fn finalize(
+        &mut self,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<FinalizeReportResult>> {
+        if self.report_label.is_none() {
+            return Ok(None);
+        }
+        let stats = &self.stats;
+        let mut barcodes = serde_json::Map::new();
+        for (barcode, name, index) in self.iter_barcodes() {
+            barcodes.insert(
+                self.display_barcode(barcode),
+                serde_json::json!({
+                    "name": name,
+                    "perfect": stats.perfect[index as usize],
+                    "corrected": stats.corrected[index as usize],
+                }),
+            );
+        }
+        let mut unknown_barcodes: Vec<(&Vec<u8>, &usize)> = stats.unknown_barcodes.iter().collect();
+        unknown_barcodes.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let top_unknown_barcodes: Vec<serde_json::Value> = unknown_barcodes
+            .iter()
+            .take(self.report_top_unknown_barcodes)
+            .map(|(barcode, count)| {
+                let closest = self
+                    .barcode_to_name
+                    .iter()
+                    .filter(|(known, _name)| known.len() == barcode.len())
+                    .map(|(known, name)| (self.distance(known, barcode), known, name))
+                    .min_by_key(|(distance, _known, _name)| *distance);
+                serde_json::json!({
+                    "barcode": self.display_barcode(barcode),
+                    "count": count,
+                    "closest_barcode": closest.map(|(_, known, _)| self.display_barcode(known)),
+                    "closest_name": closest.map(|(_, _, name)| name),
+                    "mismatches": closest.map(|(distance, _, _)| distance),
+                })
+            })
+            .collect();
+
+        let mut contents = serde_json::Map::new();
+        contents.insert(
+            "perfect_reads".to_string(),
+            stats.perfect.iter().sum::<usize>().into(),
+        );
+        contents.insert(
+            "corrected_reads".to_string(),
+            stats.corrected.iter().sum::<usize>().into(),
+        );
+        // which index carried the mismatches
+        contents.insert(
+            "reads_with_mismatches_per_region".to_string(),
+            stats.reads_with_mismatches_per_region.clone().into(),
+        );
+        contents.insert(
+            "mismatch_histogram".to_string(),
+            stats.mismatch_histogram.clone().into(),
+        );
+        contents.insert("ambiguous_reads".to_string(), stats.ambiguous.into());
+        contents.insert("unmatched_reads".to_string(), stats.unmatched.into());
+        contents.insert("barcodes".to_string(), barcodes.into());
+        contents.insert(
+            "colliding_barcodes".to_string(),
+            self.colliding_barcodes
+                .iter()
+                .map(|(a, b)| serde_json::json!([self.display_barcode(a), self.display_barcode(b)]))
+                .collect::<Vec<_>>()
+                .into(),
+        );
+        contents.insert(
+            "top_unknown_barcodes".to_string(),
+            top_unknown_barcodes.into(),
+        );
+        Ok(Some(FinalizeReportResult {
+            report_no: self.report_no,
+            contents: serde_json::Value::Object(contents),
+        }))
+    }
+}
+
+/// Read barcode -> sample name from a sample sheet.
+///
+/// The sheet is tab or comma separated, with a header naming the columns
+/// `Sample_ID`, `index` and (optionally) `index2`. Other columns are ignored.
+/// For Illumina (v2) sample sheets, only the `[BCLConvert_Data]` section is read.
+///
+/// The barcode is index followed by index2, matching a tag extracted from
+/// the index1 region followed by the index2 region.
+fn read_sample_sheet(filename: &str) -> Result<BTreeMap<Vec<u8>, String>> {
+    let raw = std::fs::read_to_string(filename)
+        .with_context(|| format!("Could not read sample sheet {filename}"))?;
+    let mut lines: Vec<(usize, &str)> = raw.lines().enumerate().collect();
+    if let Some(section_start) = lines.iter().position(|(_, line)| {
+        line.trim()
+            .trim_end_matches(',')
+            .eq_ignore_ascii_case("[BCLConvert_Data]")
+    }) {
+        lines.drain(..=section_start);
+        if let Some(section_end) = lines
+            .iter()
+            .position(|(_, line)| line.trim_start().starts_with('['))
+        {
+            lines.truncate(section_end);
+        }
+    }
+    let mut lines = lines.into_iter().filter(|(_, line)| {
+        let line = line.trim();
+        !(line.is_empty() || line.starts_with('#') || line.chars().all(|c| c == ','))
+    });
+    let Some((_, header)) = lines.next() else {
+        bail!("Sample sheet {filename} is empty.");
+    };
+    let separator = if header.contains('\t') { '\t' } else { ',' };
+    let header: Vec<String> = header
+        .split(separator)
+        .map(|x| x.trim().to_lowercase())
+        .collect();
+    let column = |name: &str| header.iter().position(|x| x == name);
+    let (Some(name_column), Some(index_column)) = (column("sample_id"), column("index")) else {
+        bail!("Sample sheet {filename}: header needs 'Sample_ID' and 'index' columns.");
+    };
+    let index2_column = column("index2");
+
+    let mut res: BTreeMap<Vec<u8>, String> = BTreeMap::new();
+    let mut index_lens: Option<(usize, usize)> = None;
+    for (line_no, line) in lines {
+        let fields: Vec<&str> = line.split(separator).map(str::trim).collect();
+        let field = |column: usize| fields.get(column).copied().unwrap_or_default();
+        let name = field(name_column);
+        if name.is_empty() {
+            bail!(
+                "Sample sheet {filename}, line {}: empty Sample_ID.",
+                line_no + 1
+            );
+        }
+        let mut indices = Vec::new();
+        for column in std::iter::once(index_column).chain(index2_column) {
+            let index = field(column).to_uppercase().into_bytes();
+            if !crate::dna::all_iupac(&index) {
+                bail!(
+                    "Sample sheet {filename}, line {}: invalid index '{}' for sample '{name}'. Expected IUPAC bases.",
+                    line_no + 1,
+                    field(column)
+                );
+            }
+            indices.push(index);
+        }
+        let lens = (indices[0].len(), indices.get(1).map_or(0, Vec::len));
+        if lens.0 == 0 {
+            bail!(
+                "Sample sheet {filename}, line {}: no index for sample '{name}'.",
+                line_no + 1
+            );
+        }
+        match index_lens {
+            None => index_lens = Some(lens),
+            Some(expected) if expected != lens => bail!(
+                "Sample sheet {filename}, line {}: index lengths {}+{} for sample '{name}' differ from the previous samples ({}+{}).",
+                line_no + 1,
+                lens.0,
+                lens.1,
+                expected.0,
+                expected.1
+            ),
+            Some(_) => {}
+        }
+        let barcode = indices.concat();
+        // the same sample may be listed once per lane
+        if res.get(&barcode).is_some_and(|other| other != name) {
+            bail!(
+                "Sample sheet {filename}, line {}: samples '{}' and '{name}' share the barcode {}.",
+                line_no + 1,
+                res[&barcode],
+                String::from_utf8_lossy(&barcode)
+            );
+        }
+        res.insert(barcode, name.to_string());
+    }
+    if res.is_empty() {
+        bail!("Sample sheet {filename} contains no samples.");
+    }
+    Ok(res)
 }
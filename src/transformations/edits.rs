@@ -1,17 +1,27 @@
 use super::{
-    NewLocation, Step, Target, Transformation, apply_in_place, apply_in_place_wrapped,
-    filter_tag_locations, filter_tag_locations_all_targets,
-    filter_tag_locations_beyond_read_length, validate_target,
+    FinalizeReportResult, InputInfo, NewLocation, Step, Target, Transformation, apply_in_place,
+    apply_in_place_wrapped, filter_tag_locations, filter_tag_locations_all_targets,
+    filter_tag_locations_beyond_read_length, reports::error_probability, validate_max_error_rate,
+    validate_target,
 };
 use crate::{
     config::deser::{
         base_or_dot, dna_from_string, u8_from_char_or_number, u8_from_string, u8_regex_from_string,
     },
-    demultiplex::Demultiplexed,
-    dna::HitRegion,
+    demultiplex::{DemultiplexInfo, Demultiplexed},
+    dna::{
+        Anchor, DetectedAdapter, Hit, HitRegion, Hits, align_adapter_3prime, align_adapter_5prime,
+        align_iupac, all_iupac, correct_pair_overlap, detect_adapter,
+        detect_adapter_from_read_through, find_pair_overlap, iupac_hamming_distance, merge_pair,
+        reverse_complement_iupac,
+    },
 };
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
 use serde_valid::Validate;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
 /* fn default_readname_end_chars() -> Vec<u8> {
     vec![b' ', b'/']
@@ -350,7 +360,11 @@ impl Step for Rename {
 pub struct TrimAdapterMismatchTail {
     pub target: Target,
     pub min_length: usize,
+    #[serde(default)]
     pub max_mismatches: usize,
+    /// allow indels, with up to this many errors per aligned adapter base
+    #[serde(default)]
+    pub max_error_rate: Option<f32>,
     #[serde(deserialize_with = "dna_from_string")]
     pub query: Vec<u8>,
 }
@@ -368,6 +382,7 @@ impl Step for TrimAdapterMismatchTail {
         if self.min_length > self.query.len() {
             bail!("Min length must be <= query length");
         }
+        validate_max_error_rate(self.max_error_rate, self.max_mismatches > 0)?;
         validate_target(self.target, input_def)
     }
     fn apply(
@@ -376,15 +391,1294 @@ impl Step for TrimAdapterMismatchTail {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
+        apply_in_place_wrapped(
+            self.target,
+            |read| match self.max_error_rate {
+                Some(max_error_rate) => {
+                    read.trim_adapter_with_indels(&self.query, self.min_length, max_error_rate);
+                }
+                None => {
+                    read.trim_adapter_mismatch_tail(
+                        &self.query,
+                        self.min_length,
+                        self.max_mismatches,
+                    );
+                }
+            },
+            &mut block,
+        );
+        (block, true)
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdapterDetection {
+    /// the most common sequence following inserts of varying length
+    #[serde(alias = "over_represented")]
+    #[default]
+    OverRepresented,
+    /// the sequence beyond the insert end, where read1 and read2 overlap
+    #[serde(alias = "pair_overlap")]
+    PairOverlap,
+}
+
+// fastp's defaults for the overlap analysis
+fn default_auto_detect_min_overlap() -> usize {
+    30
+}
+
+fn default_auto_detect_overlap_max_mismatches() -> usize {
+    5
+}
+
+fn default_auto_detect_overlap_max_mismatch_rate() -> f32 {
+    0.2
+}
+
+#[derive(serde::Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TrimAdapterAutoDetect {
+    pub target: Target,
+    #[serde(default)]
+    pub detection: AdapterDetection,
+    /// detection = 'PairOverlap': see `TrimAdapterByOverlap`
+    #[serde(default = "default_auto_detect_min_overlap")]
+    #[validate(minimum = 1)]
+    pub min_overlap: usize,
+    #[serde(default = "default_auto_detect_overlap_max_mismatches")]
+    pub overlap_max_mismatches: usize,
+    #[serde(default = "default_auto_detect_overlap_max_mismatch_rate")]
+    #[validate(minimum = 0.)]
+    #[validate(maximum = 1.)]
+    pub overlap_max_mismatch_rate: f32,
+    /// detect the adapter in the first this many molecules reaching the step
+    #[validate(minimum = 1)]
+    pub sample_size: usize,
+    /// fraction of the sampled reads that must contain the adapter
+    #[validate(minimum = 0.)]
+    #[validate(maximum = 1.)]
+    pub min_frequency: f64,
+    #[validate(minimum = 1)]
+    pub min_length: usize,
+    pub max_mismatches: usize,
+    /// key of the detected adapter in the json report
+    pub report_label: String,
+    #[serde(skip)]
+    pub report_no: usize,
+    #[serde(skip)]
+    pub sampled_reads: usize,
+    #[serde(skip)]
+    pub adapter: Option<DetectedAdapter>,
+    /// the molecules held back until we have `sample_size` of them
+    #[serde(skip)]
+    sample: Option<crate::io::FastQBlocksCombined>,
+    #[serde(skip)]
+    detection_done: bool,
+}
+
+impl std::fmt::Debug for TrimAdapterAutoDetect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrimAdapterAutoDetect")
+            .field("target", &self.target)
+            .field("detection", &self.detection)
+            .field("min_overlap", &self.min_overlap)
+            .field("overlap_max_mismatches", &self.overlap_max_mismatches)
+            .field("overlap_max_mismatch_rate", &self.overlap_max_mismatch_rate)
+            .field("sample_size", &self.sample_size)
+            .field("min_frequency", &self.min_frequency)
+            .field("min_length", &self.min_length)
+            .field("max_mismatches", &self.max_mismatches)
+            .field("report_label", &self.report_label)
+            .field("adapter", &self.adapter)
+            .finish()
+    }
+}
+
+impl Clone for TrimAdapterAutoDetect {
+    fn clone(&self) -> Self {
+        Self {
+            target: self.target,
+            detection: self.detection,
+            min_overlap: self.min_overlap,
+            overlap_max_mismatches: self.overlap_max_mismatches,
+            overlap_max_mismatch_rate: self.overlap_max_mismatch_rate,
+            sample_size: self.sample_size,
+            min_frequency: self.min_frequency,
+            min_length: self.min_length,
+            max_mismatches: self.max_mismatches,
+            report_label: self.report_label.clone(),
+            report_no: self.report_no,
+            sampled_reads: self.sampled_reads,
+            adapter: self.adapter.clone(),
+            sample: None,
+            detection_done: self.detection_done,
+        }
+    }
+}
+
+impl TrimAdapterAutoDetect {
+    /// Detect the adapter in the first `sample_size` molecules of `block`
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    fn detect(&mut self, block: &crate::io::FastQBlocksCombined) {
+        let sample_len = block.len().min(self.sample_size);
+        self.sampled_reads = sample_len;
+        let min_count = (self.min_frequency * sample_len as f64).ceil() as usize;
+        self.detection_done = true;
+        if self.detection == AdapterDetection::PairOverlap {
+            let mut adapter_ends = Vec::new();
+            let mut block_iter = block.get_pseudo_iter();
+            for _ in 0..sample_len {
+                let molecule = block_iter.pseudo_next().expect("sample_len <= block.len()");
+                let read2 = molecule.read2.as_ref().expect("read2 checked in validate");
+                let overlap = find_pair_overlap(
+                    molecule.read1.seq(),
+                    &reverse_complement_iupac(read2.seq()),
+                    self.min_overlap,
+                    self.overlap_max_mismatches,
+                    self.overlap_max_mismatch_rate,
+                );
+                let read = if self.target == Target::Read1 {
+                    &molecule.read1
+                } else {
+                    read2
+                };
+                // anything beyond the insert is adapter
+                if let Some(insert_size) = overlap
+                    .map(|overlap| overlap.insert_size(read2.len()))
+                    .filter(|insert_size| *insert_size < read.len())
+                {
+                    adapter_ends.push(read.seq()[insert_size..].to_vec());
+                }
+            }
+            self.adapter = detect_adapter_from_read_through(&adapter_ends, min_count);
+            return;
+        }
+        let reads = match self.target {
+            Target::Read1 => &block.read1,
+            Target::Read2 => block
+                .read2
+                .as_ref()
+                .expect("Input def and transformation def mismatch"),
+            Target::Index1 => block
+                .index1
+                .as_ref()
+                .expect("Input def and transformation def mismatch"),
+            Target::Index2 => block
+                .index2
+                .as_ref()
+                .expect("Input def and transformation def mismatch"),
+        };
+        let sample: Vec<Vec<u8>> = (0..sample_len)
+            .map(|ii| reads.get(ii).seq().to_vec())
+            .collect();
+        self.adapter = detect_adapter(&sample, min_count);
+    }
+
+    fn trim(&self, block: &mut crate::io::FastQBlocksCombined) {
+        if let Some(adapter) = &self.adapter {
+            apply_in_place_wrapped(
+                self.target,
+                |read| {
+                    read.trim_adapter_mismatch(
+                        &adapter.sequence,
+                        self.min_length,
+                        self.max_mismatches,
+                    );
+                },
+                block,
+            );
+        }
+    }
+}
+
+impl Step for TrimAdapterAutoDetect {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        if self.max_mismatches > self.min_length {
+            bail!("Max mismatches must be <= min length");
+        }
+        if self.detection == AdapterDetection::PairOverlap {
+            if !input_def.has_read2() {
+                bail!(
+                    "Read2 is not defined in the input section, but TrimAdapterAutoDetect with detection = 'PairOverlap' needs it"
+                );
+            }
+            if !matches!(self.target, Target::Read1 | Target::Read2) {
+                bail!("TrimAdapterAutoDetect with detection = 'PairOverlap' only works on Read1 or Read2");
+            }
+        }
+        validate_target(self.target, input_def)
+    }
+
+    // the sample must be the first molecules, and the report needs finalize
+    fn needs_serial(&self) -> bool {
+        true
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        if !self.detection_done {
+            // hold the molecules back (passing on empty blocks)
+            // until we have seen enough of them
+            if let Some(mut sample) = self.sample.take() {
+                sample.append(block);
+                block = sample;
+            }
+            if block.len() < self.sample_size {
+                self.sample = Some(block.take_molecules());
+                return (block, true);
+            }
+            self.detect(&block);
+        }
+        self.trim(&mut block);
+        (block, true)
+    }
+
+    fn flush(&mut self) -> Option<crate::io::FastQBlocksCombined> {
+        // fewer than sample_size molecules in total
+        let mut block = self.sample.take()?;
+        self.detect(&block);
+        self.trim(&mut block);
+        Some(block)
+    }
+
+    fn report_label(&self) -> Option<String> {
+        Some(self.report_label.clone())
+    }
+
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
+
+    fn finalize(
+        &mut self,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<FinalizeReportResult>> {
+        let mut contents = serde_json::Map::new();
+        contents.insert("sampled_reads".to_string(), self.sampled_reads.into());
+        contents.insert(
+            "detected_adapter".to_string(),
+            self.adapter
+                .as_ref()
+                .map_or(serde_json::Value::Null, |adapter| {
+                    // PairOverlap read-throughs are taken from the reads as they are, N and all
+                    String::from_utf8_lossy(&adapter.sequence).into()
+                }),
+        );
+        contents.insert(
+            "supporting_reads".to_string(),
+            self.adapter
+                .as_ref()
+                .map_or(0, |adapter| adapter.supporting_reads)
+                .into(),
+        );
+        Ok(Some(FinalizeReportResult {
+            report_no: self.report_no,
+            contents: serde_json::Value::Object(contents),
+        }))
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdapterKind {
+    /// trim the adapter and everything after it
+    #[serde(alias = "three_prime")]
+    #[default]
+    ThreePrime,
+    /// trim the adapter and everything before it
+    #[serde(alias = "five_prime")]
+    FivePrime,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdapterDefinition {
+    pub name: String,
+    /// cutadapt notation: '^ADAPTER' anchors a 5' adapter to the read start,
+    /// 'ADAPTER$' a 3' adapter to the read end,
+    /// 'FIVE...THREE' links a 5' and a 3' adapter.
+    pub sequence: String,
+    #[serde(default)]
+    pub kind: AdapterKind,
+}
+
+#[derive(Debug, Clone)]
+struct AdapterPart {
+    sequence: Vec<u8>,
+    anchored: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParsedAdapter {
+    name: String,
+    five_prime: Option<AdapterPart>,
+    three_prime: Option<AdapterPart>,
+}
+
+/// Where to cut a read, and how well the adapter matched
+struct AdapterMatch {
+    keep_start: usize,
+    keep_end: usize,
+    matches: usize,
+    errors: usize,
+}
+
+impl ParsedAdapter {
+    fn parse(name: &str, notation: &str, kind: AdapterKind) -> Result<ParsedAdapter> {
+        let part = |sequence: &str, anchored: bool| -> Result<AdapterPart> {
+            let sequence = sequence.to_uppercase().into_bytes();
+            if sequence.is_empty() || !all_iupac(&sequence) {
+                bail!("Adapter '{name}': invalid sequence '{notation}'. Expected IUPAC bases.");
+            }
+            Ok(AdapterPart { sequence, anchored })
+        };
+        let (five_prime, three_prime) = if let Some((five, three)) = notation.split_once("...") {
+            let (five, five_anchored) = strip_anchor_start(five);
+            let (three, three_anchored) = strip_anchor_end(three);
+            (
+                Some(part(five, five_anchored)?),
+                Some(part(three, three_anchored)?),
+            )
+        } else {
+            match kind {
+                AdapterKind::ThreePrime => {
+                    if notation.starts_with('^') {
+                        bail!("Adapter '{name}': '^' anchors 5' adapters. Set kind = 'FivePrime'.");
+                    }
+                    let (three, anchored) = strip_anchor_end(notation);
+                    (None, Some(part(three, anchored)?))
+                }
+                AdapterKind::FivePrime => {
+                    if notation.ends_with('$') {
+                        bail!(
+                            "Adapter '{name}': '$' anchors 3' adapters. Set kind = 'ThreePrime'."
+                        );
+                    }
+                    let (five, anchored) = strip_anchor_start(notation);
+                    (Some(part(five, anchored)?), None)
+                }
+            }
+        };
+        Ok(ParsedAdapter {
+            name: name.to_string(),
+            five_prime,
+            three_prime,
+        })
+    }
+
+    /// Linked adapters require the 5' adapter, the 3' one is optional.
+    fn find(&self, seq: &[u8], min_length: usize, max_error_rate: f32) -> Option<AdapterMatch> {
+        let mut res = AdapterMatch {
+            keep_start: 0,
+            keep_end: seq.len(),
+            matches: 0,
+            errors: 0,
+        };
+        if let Some(five_prime) = &self.five_prime {
+            let hit = if five_prime.anchored {
+                align_iupac(&five_prime.sequence, seq, Anchor::Left, max_error_rate)
+            } else {
+                align_adapter_5prime(&five_prime.sequence, seq, min_length, max_error_rate)
+            }?;
+            res.keep_start = hit.end;
+            res.matches += hit.query_len.saturating_sub(hit.errors);
+            res.errors += hit.errors;
+        }
+        if let Some(three_prime) = &self.three_prime {
+            let remainder = &seq[res.keep_start..];
+            let hit = if three_prime.anchored {
+                align_iupac(
+                    &three_prime.sequence,
+                    remainder,
+                    Anchor::Right,
+                    max_error_rate,
+                )
+            } else {
+                align_adapter_3prime(&three_prime.sequence, remainder, min_length, max_error_rate)
+            };
+            match hit {
+                Some(hit) => {
+                    res.keep_end = res.keep_start + hit.start;
+                    res.matches += hit.query_len.saturating_sub(hit.errors);
+                    res.errors += hit.errors;
+                }
+                None if self.five_prime.is_none() => return None,
+                None => {}
+            }
+        }
+        Some(res)
+    }
+}
+
+/// Move tag locations after cutting a per-read number of bases from the start.
+fn shift_tag_locations_after_cut_start(
+    block: &mut crate::io::FastQBlocksCombined,
+    target: Target,
+    cut_starts: &[usize],
+) {
+    filter_tag_locations(
+        block,
+        target,
+        |location: &HitRegion, pos, _seq, _read_len: usize| -> NewLocation {
+            let cut_start = cut_starts[pos];
+            if location.start < cut_start {
+                NewLocation::Remove
+            } else if cut_start == 0 {
+                NewLocation::Keep
+            } else {
+                NewLocation::New(HitRegion {
+                    start: location.start - cut_start,
+                    len: location.len,
+                    target: location.target,
+                })
+            }
+        },
+    );
+}
+
+fn strip_anchor_start(notation: &str) -> (&str, bool) {
+    match notation.strip_prefix('^') {
+        Some(stripped) => (stripped, true),
+        None => (notation, false),
+    }
+}
+
+fn strip_anchor_end(notation: &str) -> (&str, bool) {
+    match notation.strip_suffix('$') {
+        Some(stripped) => (stripped, true),
+        None => (notation, false),
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TrimAdapters {
+    pub target: Target,
+    #[serde(default)]
+    pub adapters: Vec<AdapterDefinition>,
+    /// FASTA file of further adapters, named by the first word of their header
+    #[serde(default)]
+    pub adapter_fasta: Option<String>,
+    /// kind of the adapters in `adapter_fasta`
+    #[serde(default)]
+    pub adapter_fasta_kind: AdapterKind,
+    /// errors (substitutions and indels) per aligned adapter base
+    pub max_error_rate: f32,
+    /// minimum overlap of partial adapters at the read ends
+    #[validate(minimum = 1)]
+    pub min_length: usize,
+    /// key of the per-adapter hit counts in the json report
+    pub report_label: String,
+    #[serde(skip)]
+    pub parsed_adapters: Vec<ParsedAdapter>,
+    #[serde(skip)]
+    pub report_no: usize,
+    #[serde(skip)]
+    pub read_count: usize,
+    #[serde(skip)]
+    pub hit_counts: Vec<usize>,
+}
+
+impl TrimAdapters {
+    fn parse_adapters(&self) -> Result<Vec<ParsedAdapter>> {
+        let mut parsed = Vec::new();
+        for adapter in &self.adapters {
+            parsed.push(ParsedAdapter::parse(
+                &adapter.name,
+                &adapter.sequence,
+                adapter.kind,
+            )?);
+        }
+        if let Some(adapter_fasta) = &self.adapter_fasta {
+            for (name, sequence) in crate::io::read_named_sequences(adapter_fasta)
+                .with_context(|| format!("Could not read adapter_fasta {adapter_fasta}"))?
+            {
+                parsed.push(ParsedAdapter::parse(
+                    &name,
+                    &String::from_utf8_lossy(&sequence),
+                    self.adapter_fasta_kind,
+                )?);
+            }
+        }
+        if parsed.is_empty() {
+            bail!("TrimAdapters needs at least one adapter (adapters or adapter_fasta).");
+        }
+        let mut seen = HashSet::new();
+        for adapter in &parsed {
+            if !seen.insert(&adapter.name) {
+                bail!("TrimAdapters: adapter name '{}' is used twice. Names must be unique, they are used in the report.", adapter.name);
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Step for TrimAdapters {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        validate_max_error_rate(Some(self.max_error_rate), false)?;
+        if self.adapter_fasta.is_none() {
+            // otherwise checked in init, once the file has been read.
+            self.parse_adapters()?;
+        }
+        validate_target(self.target, input_def)
+    }
+
+    fn init(
+        &mut self,
+        _input_info: &InputInfo,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<DemultiplexInfo>> {
+        self.parsed_adapters = self.parse_adapters()?;
+        self.hit_counts = vec![0; self.parsed_adapters.len()];
+        Ok(None)
+    }
+
+    // for the report
+    fn needs_serial(&self) -> bool {
+        true
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        let mut cut_starts = Vec::new();
+        let adapters = &self.parsed_adapters;
+        let hit_counts = &mut self.hit_counts;
         apply_in_place_wrapped(
             self.target,
             |read| {
-                read.trim_adapter_mismatch_tail(&self.query, self.min_length, self.max_mismatches);
+                // best = most matching bases, then fewest errors, then first in the list
+                let best = adapters
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(idx, adapter)| {
+                        adapter
+                            .find(read.seq(), self.min_length, self.max_error_rate)
+                            .map(|found| (idx, found))
+                    })
+                    .min_by_key(|(idx, found)| {
+                        (std::cmp::Reverse(found.matches), found.errors, *idx)
+                    });
+                match best {
+                    Some((idx, found)) => {
+                        hit_counts[idx] += 1;
+                        read.cut_end(read.len() - found.keep_end);
+                        read.cut_start(found.keep_start);
+                        cut_starts.push(found.keep_start);
+                    }
+                    None => cut_starts.push(0),
+                }
             },
             &mut block,
         );
+        self.read_count += cut_starts.len();
+
+        shift_tag_locations_after_cut_start(&mut block, self.target, &cut_starts);
+        filter_tag_locations_beyond_read_length(&mut block, self.target);
+
+        (block, true)
+    }
+
+    fn report_label(&self) -> Option<String> {
+        Some(self.report_label.clone())
+    }
+
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
+
+    fn finalize(
+        &mut self,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<FinalizeReportResult>> {
+        let mut contents = serde_json::Map::new();
+        contents.insert("read_count".to_string(), self.read_count.into());
+        contents.insert(
+            "trimmed_reads".to_string(),
+            self.hit_counts.iter().sum::<usize>().into(),
+        );
+        let adapters: serde_json::Map<String, serde_json::Value> = self
+            .parsed_adapters
+            .iter()
+            .zip(&self.hit_counts)
+            .map(|(adapter, count)| (adapter.name.clone(), (*count).into()))
+            .collect();
+        contents.insert("adapters".to_string(), adapters.into());
+        Ok(Some(FinalizeReportResult {
+            report_no: self.report_no,
+            contents: serde_json::Value::Object(contents),
+        }))
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TrimAdapterByOverlap {
+    #[validate(minimum = 1)]
+    pub min_overlap: usize,
+    pub max_mismatches: usize,
+    #[validate(minimum = 0.)]
+    #[validate(maximum = 1.)]
+    pub max_mismatch_rate: f32,
+}
+
+impl Step for TrimAdapterByOverlap {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        if !input_def.has_read2() {
+            bail!(
+                "Read2 is not defined in the input section, but used by transformation TrimAdapterByOverlap"
+            );
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        let mut insert_sizes = Vec::new();
+        let mut block_iter = block.get_pseudo_iter();
+        while let Some(molecule) = block_iter.pseudo_next() {
+            let read2 = molecule.read2.as_ref().expect("read2 checked in validate");
+            let overlap = find_pair_overlap(
+                molecule.read1.seq(),
+                &reverse_complement_iupac(read2.seq()),
+                self.min_overlap,
+                self.max_mismatches,
+                self.max_mismatch_rate,
+            );
+            insert_sizes.push(overlap.map(|overlap| overlap.insert_size(read2.len())));
+        }
+        // anything beyond the insert is adapter
+        for (read, insert_size) in block.read1.entries.iter_mut().zip(&insert_sizes) {
+            if let Some(insert_size) = insert_size {
+                read.max_len(*insert_size);
+            }
+        }
+        for (read, insert_size) in block
+            .read2
+            .as_mut()
+            .unwrap()
+            .entries
+            .iter_mut()
+            .zip(&insert_sizes)
+        {
+            if let Some(insert_size) = insert_size {
+                read.max_len(*insert_size);
+            }
+        }
+        filter_tag_locations_beyond_read_length(&mut block, Target::Read1);
+        filter_tag_locations_beyond_read_length(&mut block, Target::Read2);
+        (block, true)
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct MergeReads {
+    /// tag set on pairs that could not be merged
+    pub label: String,
+    /// key of the merge statistics in the json report
+    pub report_label: String,
+    #[validate(minimum = 1)]
+    pub min_overlap: usize,
+    pub max_mismatches: usize,
+    #[validate(minimum = 0.)]
+    #[validate(maximum = 1.)]
+    pub max_mismatch_rate: f32,
+    #[serde(skip)]
+    pub report_no: usize,
+    #[serde(skip)]
+    pub pair_count: usize,
+    #[serde(skip)]
+    pub merged_count: usize,
+    #[serde(skip)]
+    pub overlap_length_histogram: Vec<usize>,
+}
+
+impl Step for MergeReads {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        if !input_def.has_read2() {
+            bail!(
+                "Read2 is not defined in the input section, but used by transformation MergeReads"
+            );
+        }
+        Ok(())
+    }
+
+    fn sets_tag(&self) -> Option<String> {
+        Some(self.label.clone())
+    }
+
+    fn tag_provides_location(&self) -> bool {
+        false
+    }
+
+    // for the statistics
+    fn needs_serial(&self) -> bool {
+        true
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        let mut merged = Vec::new();
+        let mut block_iter = block.get_pseudo_iter();
+        while let Some(molecule) = block_iter.pseudo_next() {
+            let read2 = molecule.read2.as_ref().expect("read2 checked in validate");
+            let read2_rc = reverse_complement_iupac(read2.seq());
+            let overlap = find_pair_overlap(
+                molecule.read1.seq(),
+                &read2_rc,
+                self.min_overlap,
+                self.max_mismatches,
+                self.max_mismatch_rate,
+            );
+            merged.push(overlap.map(|overlap| {
+                if self.overlap_length_histogram.len() <= overlap.len {
+                    self.overlap_length_histogram.resize(overlap.len + 1, 0);
+                }
+                self.overlap_length_histogram[overlap.len] += 1;
+                let qual2_rev: Vec<u8> = read2.qual().iter().rev().copied().collect();
+                merge_pair(
+                    molecule.read1.seq(),
+                    molecule.read1.qual(),
+                    &read2_rc,
+                    &qual2_rev,
+                    &overlap,
+                )
+            }));
+        }
+        self.pair_count += merged.len();
+        self.merged_count += merged.iter().filter(|x| x.is_some()).count();
+
+        let unmerged_tags: Vec<Option<Hits>> = merged
+            .iter()
+            .map(|merged| {
+                if merged.is_none() {
+                    Some(Hits::new_multiple(vec![Hit {
+                        location: None,
+                        sequence: b"unmerged".to_vec(),
+                    }]))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        {
+            let mut merged = merged.iter();
+            block.read2.as_mut().unwrap().apply_mut(|read| {
+                if merged.next().unwrap().is_some() {
+                    read.replace_seq(Vec::new(), Vec::new());
+                }
+            });
+        }
+        {
+            let mut merged = merged.into_iter();
+            block.read1.apply_mut(|read| {
+                if let Some((seq, qual)) = merged.next().unwrap() {
+                    read.replace_seq(seq, qual);
+                }
+            });
+        }
+        // read1 may have been shortened to the insert, read2 is empty for merged pairs
+        filter_tag_locations_beyond_read_length(&mut block, Target::Read1);
+        filter_tag_locations_beyond_read_length(&mut block, Target::Read2);
+        block
+            .tags
+            .get_or_insert_with(HashMap::new)
+            .insert(self.label.clone(), unmerged_tags);
+        (block, true)
+    }
+
+    fn report_label(&self) -> Option<String> {
+        Some(self.report_label.clone())
+    }
+
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn finalize(
+        &mut self,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<FinalizeReportResult>> {
+        let mut contents = serde_json::Map::new();
+        contents.insert("pair_count".to_string(), self.pair_count.into());
+        contents.insert("merged_count".to_string(), self.merged_count.into());
+        contents.insert(
+            "merge_rate".to_string(),
+            if self.pair_count > 0 {
+                (self.merged_count as f64 / self.pair_count as f64).into()
+            } else {
+                0.0.into()
+            },
+        );
+        contents.insert(
+            "overlap_length_histogram".to_string(),
+            self.overlap_length_histogram.clone().into(),
+        );
+        Ok(Some(FinalizeReportResult {
+            report_no: self.report_no,
+            contents: serde_json::Value::Object(contents),
+        }))
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct CorrectByOverlap {
+    /// key of the correction statistics in the json report
+    pub report_label: String,
+    #[validate(minimum = 1)]
+    pub min_overlap: usize,
+    pub max_mismatches: usize,
+    #[validate(minimum = 0.)]
+    #[validate(maximum = 1.)]
+    pub max_mismatch_rate: f32,
+    #[serde(skip)]
+    pub report_no: usize,
+    #[serde(skip)]
+    pub pair_count: usize,
+    #[serde(skip)]
+    pub overlapping_pair_count: usize,
+    #[serde(skip)]
+    pub corrected_bases_read1: usize,
+    #[serde(skip)]
+    pub corrected_bases_read2: usize,
+}
+
+impl Step for CorrectByOverlap {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        if !input_def.has_read2() {
+            bail!(
+                "Read2 is not defined in the input section, but used by transformation CorrectByOverlap"
+            );
+        }
+        Ok(())
+    }
+
+    // for the statistics
+    fn needs_serial(&self) -> bool {
+        true
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        let read2_block = block.read2.as_mut().expect("read2 checked in validate");
+        for ii in 0..block.read1.len() {
+            self.pair_count += 1;
+            let mut read1 = block.read1.get_mut(ii);
+            let mut read2 = read2_block.get_mut(ii);
+            let Some(overlap) = find_pair_overlap(
+                read1.seq(),
+                &reverse_complement_iupac(read2.seq()),
+                self.min_overlap,
+                self.max_mismatches,
+                self.max_mismatch_rate,
+            ) else {
+                continue;
+            };
+            self.overlapping_pair_count += 1;
+            if overlap.mismatches == 0 {
+                continue;
+            }
+            let (mut seq1, mut qual1) = (read1.seq().to_vec(), read1.qual().to_vec());
+            let (mut seq2, mut qual2) = (read2.seq().to_vec(), read2.qual().to_vec());
+            let (corrected1, corrected2) =
+                correct_pair_overlap(&mut seq1, &mut qual1, &mut seq2, &mut qual2, &overlap);
+            if corrected1 > 0 {
+                read1.replace_seq(seq1, qual1);
+                self.corrected_bases_read1 += corrected1;
+            }
+            if corrected2 > 0 {
+                read2.replace_seq(seq2, qual2);
+                self.corrected_bases_read2 += corrected2;
+            }
+        }
         (block, true)
     }
+
+    fn report_label(&self) -> Option<String> {
+        Some(self.report_label.clone())
+    }
+
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
+
+    fn finalize(
+        &mut self,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<FinalizeReportResult>> {
+        let mut contents = serde_json::Map::new();
+        contents.insert("pair_count".to_string(), self.pair_count.into());
+        contents.insert(
+            "overlapping_pair_count".to_string(),
+            self.overlapping_pair_count.into(),
+        );
+        contents.insert(
+            "read1".to_string(),
+            serde_json::json!({"corrected_bases": self.corrected_bases_read1}),
+        );
+        contents.insert(
+            "read2".to_string(),
+            serde_json::json!({"corrected_bases": self.corrected_bases_read2}),
+        );
+        Ok(Some(FinalizeReportResult {
+            report_no: self.report_no,
+            contents: serde_json::Value::Object(contents),
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Amplicon {
+    pub name: String,
+    pub forward: Vec<Vec<u8>>,
+    pub reverse: Vec<Vec<u8>>,
+}
+
+/// Read a primer table.
+///
+/// Either tab separated 'name, forward primer, reverse primer',
+/// or (for files ending in .bed) an ARTIC style primer scheme,
+/// 'chrom, start, end, primer name, pool, strand, sequence',
+/// where the primer names end in _LEFT / _RIGHT (optionally followed by an _alt suffix).
+fn read_primer_table(filename: &str) -> Result<Vec<Amplicon>> {
+    let raw = std::fs::read_to_string(filename)
+        .with_context(|| format!("Could not read primer table {filename}"))?;
+    let is_bed = filename.to_lowercase().ends_with(".bed");
+    let mut amplicons: Vec<Amplicon> = Vec::new();
+    for (line_no, line) in raw.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (name, forward, reverse) = if is_bed {
+            if fields.len() < 7 {
+                bail!(
+                    "Primer table {filename}, line {}: expected 7 tab separated columns (chrom, start, end, name, pool, strand, sequence).",
+                    line_no + 1
+                );
+            }
+            let primer_name = fields[3];
+            let sequence = Some(fields[6]);
+            if let Some(pos) = primer_name.find("_LEFT") {
+                (&primer_name[..pos], sequence, None)
+            } else if let Some(pos) = primer_name.find("_RIGHT") {
+                (&primer_name[..pos], None, sequence)
+            } else {
+                bail!(
+                    "Primer table {filename}, line {}: primer name '{primer_name}' does not contain _LEFT or _RIGHT.",
+                    line_no + 1
+                );
+            }
+        } else {
+            if fields.len() != 3 {
+                bail!(
+                    "Primer table {filename}, line {}: expected 3 tab separated columns (name, forward primer, reverse primer).",
+                    line_no + 1
+                );
+            }
+            (fields[0], Some(fields[1]), Some(fields[2]))
+        };
+        let to_primer = |sequence: &str| -> Result<Vec<u8>> {
+            let primer = sequence.trim().to_uppercase().into_bytes();
+            if primer.is_empty() || !all_iupac(&primer) {
+                bail!(
+                    "Primer table {filename}, line {}: invalid primer sequence '{sequence}'. Expected IUPAC bases.",
+                    line_no + 1
+                );
+            }
+            Ok(primer)
+        };
+        let idx = match amplicons.iter().position(|amplicon| amplicon.name == name) {
+            Some(idx) => idx,
+            None => {
+                amplicons.push(Amplicon {
+                    name: name.to_string(),
+                    forward: Vec::new(),
+                    reverse: Vec::new(),
+                });
+                amplicons.len() - 1
+            }
+        };
+        if let Some(forward) = forward {
+            amplicons[idx].forward.push(to_primer(forward)?);
+        }
+        if let Some(reverse) = reverse {
+            amplicons[idx].reverse.push(to_primer(reverse)?);
+        }
+    }
+    if amplicons.is_empty() {
+        bail!("Primer table {filename} contains no primers.");
+    }
+    for amplicon in &amplicons {
+        if amplicon.forward.is_empty() || amplicon.reverse.is_empty() {
+            bail!(
+                "Primer table {filename}: amplicon '{}' needs both a forward (_LEFT) and a reverse (_RIGHT) primer.",
+                amplicon.name
+            );
+        }
+    }
+    Ok(amplicons)
+}
+
+/// (mismatches, primer length) of the best primer matching the start of the read.
+/// Longer primers win ties.
+fn best_primer_at_start(primers: &[Vec<u8>], seq: &[u8]) -> Option<(usize, usize)> {
+    primers
+        .iter()
+        .filter(|primer| primer.len() <= seq.len())
+        .map(|primer| {
+            (
+                iupac_hamming_distance(primer, &seq[..primer.len()]),
+                primer.len(),
+            )
+        })
+        .min_by_key(|(mismatches, len)| (*mismatches, std::cmp::Reverse(*len)))
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TrimAmpliconPrimers {
+    /// TSV (name, forward, reverse) or ARTIC style BED file
+    pub primer_table: String,
+    /// per primer
+    pub max_mismatches: usize,
+    /// tag receiving the amplicon name
+    pub label: String,
+    /// key of the per-amplicon read counts in the json report
+    pub report_label: String,
+    #[serde(skip)]
+    pub amplicons: Vec<Amplicon>,
+    #[serde(skip)]
+    pub report_no: usize,
+    #[serde(skip)]
+    pub pair_count: usize,
+    #[serde(skip)]
+    pub amplicon_counts: Vec<usize>,
+}
+
+impl TrimAmpliconPrimers {
+    /// Identify the amplicon of a pair - either read may carry the forward primer.
+    /// Returns (amplicon index, primer length on read1, primer length on read2).
+    fn identify(&self, seq1: &[u8], seq2: &[u8]) -> Option<(usize, usize, usize)> {
+        let mut best: Option<(usize, usize, usize, usize)> = None; // mismatches, idx, cut1, cut2
+        for (idx, amplicon) in self.amplicons.iter().enumerate() {
+            for (primers1, primers2) in [
+                (&amplicon.forward, &amplicon.reverse),
+                (&amplicon.reverse, &amplicon.forward),
+            ] {
+                if let (Some((mismatches1, len1)), Some((mismatches2, len2))) = (
+                    best_primer_at_start(primers1, seq1),
+                    best_primer_at_start(primers2, seq2),
+                ) {
+                    if mismatches1 <= self.max_mismatches
+                        && mismatches2 <= self.max_mismatches
+                        && best.is_none_or(|(best_mismatches, _, _, _)| {
+                            mismatches1 + mismatches2 < best_mismatches
+                        })
+                    {
+                        best = Some((mismatches1 + mismatches2, idx, len1, len2));
+                    }
+                }
+            }
+        }
+        best.map(|(_, idx, cut1, cut2)| (idx, cut1, cut2))
+    }
+}
+
+impl Step for TrimAmpliconPrimers {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        if !input_def.has_read2() {
+            bail!(
+                "Read2 is not defined in the input section, but used by transformation TrimAmpliconPrimers"
+            );
+        }
+        Ok(())
+    }
+
+    fn init(
+        &mut self,
+        _input_info: &InputInfo,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<DemultiplexInfo>> {
+        self.amplicons = read_primer_table(&self.primer_table)?;
+        self.amplicon_counts = vec![0; self.amplicons.len()];
+        Ok(None)
+    }
+
+    fn sets_tag(&self) -> Option<String> {
+        Some(self.label.clone())
+    }
+
+    fn tag_provides_location(&self) -> bool {
+        false
+    }
+
+    // for the report
+    fn needs_serial(&self) -> bool {
+        true
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        let mut assigned = Vec::new();
+        let mut block_iter = block.get_pseudo_iter();
+        while let Some(molecule) = block_iter.pseudo_next() {
+            let read2 = molecule.read2.as_ref().expect("read2 checked in validate");
+            assigned.push(self.identify(molecule.read1.seq(), read2.seq()));
+        }
+        self.pair_count += assigned.len();
+        for (idx, _, _) in assigned.iter().flatten() {
+            self.amplicon_counts[*idx] += 1;
+        }
+
+        let cut_starts1: Vec<usize> = assigned
+            .iter()
+            .map(|hit| hit.map_or(0, |(_, cut1, _)| cut1))
+            .collect();
+        let cut_starts2: Vec<usize> = assigned
+            .iter()
+            .map(|hit| hit.map_or(0, |(_, _, cut2)| cut2))
+            .collect();
+        {
+            let mut cuts = cut_starts1.iter();
+            block
+                .read1
+                .apply_mut(|read| read.cut_start(*cuts.next().unwrap()));
+        }
+        {
+            let mut cuts = cut_starts2.iter();
+            block
+                .read2
+                .as_mut()
+                .unwrap()
+                .apply_mut(|read| read.cut_start(*cuts.next().unwrap()));
+        }
+        shift_tag_locations_after_cut_start(&mut block, Target::Read1, &cut_starts1);
+        shift_tag_locations_after_cut_start(&mut block, Target::Read2, &cut_starts2);
+
+        let amplicon_tags: Vec<Option<Hits>> = assigned
+            .iter()
+            .map(|hit| {
+                hit.map(|(idx, _, _)| {
+                    Hits::new_multiple(vec![Hit {
+                        location: None,
+                        sequence: self.amplicons[idx].name.as_bytes().to_vec(),
+                    }])
+                })
+            })
+            .collect();
+        block
+            .tags
+            .get_or_insert_with(HashMap::new)
+            .insert(self.label.clone(), amplicon_tags);
+        (block, true)
+    }
+
+    fn report_label(&self) -> Option<String> {
+        Some(self.report_label.clone())
+    }
+
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
+
+    fn finalize(
+        &mut self,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<FinalizeReportResult>> {
+        let mut contents = serde_json::Map::new();
+        contents.insert("pair_count".to_string(), self.pair_count.into());
+        let assigned: usize = self.amplicon_counts.iter().sum();
+        contents.insert(
+            "unassigned".to_string(),
+            (self.pair_count - assigned).into(),
+        );
+        let amplicons: serde_json::Map<String, serde_json::Value> = self
+            .amplicons
+            .iter()
+            .zip(&self.amplicon_counts)
+            .map(|(amplicon, count)| (amplicon.name.clone(), (*count).into()))
+            .collect();
+        contents.insert("amplicons".to_string(), amplicons.into());
+        Ok(Some(FinalizeReportResult {
+            report_no: self.report_no,
+            contents: serde_json::Value::Object(contents),
+        }))
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone, Validate)]
@@ -529,6 +1823,69 @@ impl Step for TrimQualityEnd {
     }
 }
 
+/// The (start, end) of the read segment to keep according to the modified Mott algorithm:
+/// the segment with the maximal sum of `error_limit - error probability`.
+fn mott_keep_region(quality: &[u8], error_limit: f64) -> (usize, usize) {
+    let mut best = (0, 0);
+    let mut best_score = 0.0;
+    let mut start = 0;
+    let mut score = 0.0;
+    for (ii, q) in quality.iter().enumerate() {
+        score += error_limit - error_probability(*q);
+        if score <= 0.0 {
+            score = 0.0;
+            start = ii + 1;
+        } else if score > best_score {
+            best_score = score;
+            best = (start, ii + 1);
+        }
+    }
+    best
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TrimQualityMott {
+    pub target: Target,
+    /// error probability a base may have and still count in favour of keeping it
+    #[validate(minimum = 0.)]
+    #[validate(maximum = 1.)]
+    pub error_limit: f64,
+}
+
+impl Step for TrimQualityMott {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        validate_target(self.target, input_def)
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        let mut cut_starts = Vec::new();
+        apply_in_place_wrapped(
+            self.target,
+            |read| {
+                let (start, end) = mott_keep_region(read.qual(), self.error_limit);
+                read.cut_end(read.len() - end);
+                read.cut_start(start);
+                cut_starts.push(start);
+            },
+            &mut block,
+        );
+        shift_tag_locations_after_cut_start(&mut block, self.target, &cut_starts);
+        filter_tag_locations_beyond_read_length(&mut block, self.target);
+        (block, true)
+    }
+}
+
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct SwapR1AndR2 {}
@@ -541,7 +1898,7 @@ impl Step for SwapR1AndR2 {
         _all_transforms: &[Transformation],
     ) -> Result<()> {
         {
-            if input_def.read2.is_none() {
+            if !input_def.has_read2() {
                 bail!(
                     "Read2 is not defined in the input section, but used by transformation SwapR1AndR2"
                 );
@@ -1,14 +1,21 @@
-use anyhow::Result;
-use std::{collections::HashSet, path::Path};
+use anyhow::{Context, Result, bail};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
 
 use super::{
-    FragmentEntry, FragmentEntryForCuckooFilter, InputInfo, KeepOrRemove, OurCuckCooFilter, Step,
-    Target, TargetPlusAll, Transformation, apply_filter, apply_filter_all, extend_seed,
-    reproducible_cuckoofilter, validate_target,
+    FinalizeReportResult, FragmentEntry, FragmentEntryForCuckooFilter, InputInfo, KeepOrRemove,
+    OurCuckCooFilter, Step, Target, TargetPlusAll, Transformation, apply_filter, apply_filter_all,
+    apply_read_filter, extend_seed, reports::expected_errors, reproducible_cuckoofilter,
+    validate_read_filter_target, validate_target,
 };
 use crate::{
+    config::PairedEndPolicy,
     config::deser::{option_u8_from_string, u8_from_char_or_number},
     demultiplex::{DemultiplexInfo, Demultiplexed},
+    dna::for_each_canonical_kmer,
 };
 use serde_valid::Validate;
 
@@ -28,8 +35,12 @@ impl Step for Head {
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
         let remaining = self.n - self.so_far;
+        if remaining <= block.len() {
+            // singletons from the molecules we cut off
+            block.truncate_singletons(remaining);
+        }
         if remaining == 0 {
-            (block.empty(), false)
+            (block.into_empty(), false)
         } else {
             block.resize(remaining.min(block.len()));
             let do_continue = remaining > block.len();
@@ -63,7 +74,7 @@ impl Step for Skip {
             (block, true)
         } else if remaining >= block.len() {
             self.so_far += block.len();
-            (block.empty(), true)
+            (block.into_empty(), true)
         } else {
             let here = remaining.min(block.len());
             self.so_far += here;
@@ -80,26 +91,43 @@ impl Step for Skip {
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Empty {
-    pub target: Target,
+    pub target: Option<Target>,
+    pub paired_end: Option<PairedEndPolicy>,
 }
 
 impl Step for Empty {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        validate_read_filter_target(self.target, self.paired_end, input_def)
+    }
+
     fn apply(
         &mut self,
         mut block: crate::io::FastQBlocksCombined,
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
-        apply_filter(self.target, &mut block, |read| !read.seq().is_empty());
+        apply_read_filter(self.target, self.paired_end, &mut block, |read| {
+            !read.seq().is_empty()
+        });
         (block, true)
     }
+
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        self.paired_end
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct MinLen {
     pub n: usize,
-    pub target: Target,
+    pub target: Option<Target>,
+    pub paired_end: Option<PairedEndPolicy>,
 }
 
 impl Step for MinLen {
@@ -109,7 +137,7 @@ impl Step for MinLen {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[Transformation],
     ) -> Result<()> {
-        validate_target(self.target, input_def)
+        validate_read_filter_target(self.target, self.paired_end, input_def)
     }
 
     fn apply(
@@ -118,16 +146,23 @@ impl Step for MinLen {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
-        apply_filter(self.target, &mut block, |read| read.seq().len() >= self.n);
+        apply_read_filter(self.target, self.paired_end, &mut block, |read| {
+            read.seq().len() >= self.n
+        });
         (block, true)
     }
+
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        self.paired_end
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct MaxLen {
     pub n: usize,
-    pub target: Target,
+    pub target: Option<Target>,
+    pub paired_end: Option<PairedEndPolicy>,
 }
 
 impl Step for MaxLen {
@@ -137,7 +172,7 @@ impl Step for MaxLen {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[Transformation],
     ) -> Result<()> {
-        validate_target(self.target, input_def)
+        validate_read_filter_target(self.target, self.paired_end, input_def)
     }
 
     fn apply(
@@ -146,15 +181,22 @@ impl Step for MaxLen {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
-        apply_filter(self.target, &mut block, |read| read.seq().len() <= self.n);
+        apply_read_filter(self.target, self.paired_end, &mut block, |read| {
+            read.seq().len() <= self.n
+        });
         (block, true)
     }
+
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        self.paired_end
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct MeanQuality {
-    pub target: Target,
+    pub target: Option<Target>,
+    pub paired_end: Option<PairedEndPolicy>,
     pub min: f32,
 }
 impl Step for MeanQuality {
@@ -164,7 +206,7 @@ impl Step for MeanQuality {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[Transformation],
     ) -> Result<()> {
-        validate_target(self.target, input_def)
+        validate_read_filter_target(self.target, self.paired_end, input_def)
     }
 
     #[allow(
@@ -178,7 +220,7 @@ impl Step for MeanQuality {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
-        apply_filter(self.target, &mut block, |read| {
+        apply_read_filter(self.target, self.paired_end, &mut block, |read| {
             let qual = read.qual();
             let sum: usize = qual.iter().map(|x| *x as usize).sum();
             let avg_qual = sum as f32 / qual.len() as f32;
@@ -186,6 +228,73 @@ impl Step for MeanQuality {
         });
         (block, true)
     }
+
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        self.paired_end
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct MaxExpectedErrors {
+    pub target: TargetPlusAll,
+    #[validate(minimum = 0.)]
+    pub max: f64,
+    /// divide the expected errors by the number of bases
+    #[serde(default)]
+    pub per_base: bool,
+}
+
+impl MaxExpectedErrors {
+    #[allow(clippy::cast_precision_loss)]
+    fn passes(&self, quals: &[&[u8]]) -> bool {
+        let errors: f64 = quals.iter().map(|qual| expected_errors(qual)).sum();
+        let len: usize = quals.iter().map(|qual| qual.len()).sum();
+        let errors = if self.per_base && len > 0 {
+            errors / len as f64
+        } else {
+            errors
+        };
+        errors <= self.max
+    }
+}
+
+impl Step for MaxExpectedErrors {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        if let Ok(target) = self.target.try_into() {
+            validate_target(target, input_def)?;
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        if let Ok(target) = self.target.try_into() {
+            apply_filter(target, &mut block, |read| self.passes(&[read.qual()]));
+        } else {
+            // sum over all segments of the molecule
+            apply_filter_all(&mut block, |read1, read2, index1, index2| {
+                let mut quals = vec![read1.qual()];
+                quals.extend(
+                    [read2, index1, index2]
+                        .into_iter()
+                        .flatten()
+                        .map(crate::io::WrappedFastQRead::qual),
+                );
+                self.passes(&quals)
+            });
+        }
+        (block, true)
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone, Validate)]
@@ -196,7 +305,8 @@ pub struct QualifiedBases {
     #[validate(minimum = 0.)]
     #[validate(maximum = 1.)]
     pub min_percentage: f32,
-    pub target: Target,
+    pub target: Option<Target>,
+    pub paired_end: Option<PairedEndPolicy>,
 }
 
 impl Step for QualifiedBases {
@@ -206,7 +316,7 @@ impl Step for QualifiedBases {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[Transformation],
     ) -> Result<()> {
-        validate_target(self.target, input_def)
+        validate_read_filter_target(self.target, self.paired_end, input_def)
     }
 
     #[allow(
@@ -220,7 +330,7 @@ impl Step for QualifiedBases {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
-        apply_filter(self.target, &mut block, |read| {
+        apply_read_filter(self.target, self.paired_end, &mut block, |read| {
             let qual = read.qual();
             let sum: usize = qual
                 .iter()
@@ -231,12 +341,17 @@ impl Step for QualifiedBases {
         });
         (block, true)
     }
+
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        self.paired_end
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct TooManyN {
-    pub target: Target,
+    pub target: Option<Target>,
+    pub paired_end: Option<PairedEndPolicy>,
     pub n: usize,
 }
 impl Step for TooManyN {
@@ -246,7 +361,7 @@ impl Step for TooManyN {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[Transformation],
     ) -> Result<()> {
-        validate_target(self.target, input_def)
+        validate_read_filter_target(self.target, self.paired_end, input_def)
     }
 
     fn apply(
@@ -255,19 +370,24 @@ impl Step for TooManyN {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
-        apply_filter(self.target, &mut block, |read| {
+        apply_read_filter(self.target, self.paired_end, &mut block, |read| {
             let seq = read.seq();
             let sum: usize = seq.iter().map(|x| usize::from(*x == b'N')).sum();
             sum <= self.n
         });
         (block, true)
     }
+
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        self.paired_end
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone, Validate)]
 #[serde(deny_unknown_fields)]
 pub struct LowComplexity {
-    pub target: Target,
+    pub target: Option<Target>,
+    pub paired_end: Option<PairedEndPolicy>,
     #[validate(minimum = 0.)]
     #[validate(maximum = 1.)]
     pub threshold: f32,
@@ -280,7 +400,7 @@ impl Step for LowComplexity {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[Transformation],
     ) -> Result<()> {
-        validate_target(self.target, input_def)
+        validate_read_filter_target(self.target, self.paired_end, input_def)
     }
 
     #[allow(
@@ -294,7 +414,7 @@ impl Step for LowComplexity {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
-        apply_filter(self.target, &mut block, |read| {
+        apply_read_filter(self.target, self.paired_end, &mut block, |read| {
             // Calculate the number of transitions
             let mut transitions = 0;
             let seq = read.seq();
@@ -308,6 +428,10 @@ impl Step for LowComplexity {
         });
         (block, true)
     }
+
+    fn paired_end(&self) -> Option<PairedEndPolicy> {
+        self.paired_end
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone, Validate)]
@@ -635,3 +759,209 @@ impl Step for OtherFileBySequence {
         (block, true)
     }
 }
+
+fn default_min_kmers() -> usize {
+    1
+}
+
+#[derive(serde::Deserialize, Debug, Validate, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KmerContamination {
+    pub target: TargetPlusAll,
+    pub keep_or_remove: KeepOrRemove,
+    /// fasta files, counted separately in the report
+    pub references: Vec<String>,
+    #[validate(minimum = 1)]
+    #[validate(maximum = 32)]
+    pub k: usize,
+    #[serde(default = "default_min_kmers")]
+    #[validate(minimum = 1)]
+    pub min_kmers: usize,
+    #[serde(default)]
+    #[validate(minimum = 0.)]
+    #[validate(maximum = 1.)]
+    pub min_kmer_fraction: f64,
+    /// key of the per-reference hit counts in the json report
+    pub report_label: String,
+
+    /// canonical k-mer -> index of the first reference containing it.
+    /// Read only after init, shared between the copies of this step.
+    #[serde(skip)]
+    pub kmers: Arc<HashMap<u64, usize>>,
+    #[serde(skip)]
+    pub report_no: usize,
+    #[serde(skip)]
+    pub molecule_count: usize,
+    #[serde(skip)]
+    pub hit_counts: Vec<usize>,
+}
+
+/// Which reference (if any) shares enough k-mers with the sequences?
+/// The one with the most shared k-mers wins, ties go to the first one.
+#[allow(clippy::cast_precision_loss)]
+fn kmer_contamination_hit(
+    kmers: &HashMap<u64, usize>,
+    reference_count: usize,
+    k: usize,
+    min_kmers: usize,
+    min_kmer_fraction: f64,
+    seqs: &[&[u8]],
+) -> Option<usize> {
+    let mut total = 0;
+    let mut shared = vec![0; reference_count];
+    for seq in seqs {
+        for_each_canonical_kmer(seq, k, |kmer| {
+            total += 1;
+            if let Some(reference) = kmers.get(&kmer) {
+                shared[*reference] += 1;
+            }
+        });
+    }
+    let shared_total: usize = shared.iter().sum();
+    if shared_total < min_kmers || (shared_total as f64) < min_kmer_fraction * total as f64 {
+        return None;
+    }
+    shared
+        .iter()
+        .enumerate()
+        .max_by_key(|(idx, count)| (**count, std::cmp::Reverse(*idx)))
+        .map(|(idx, _)| idx)
+}
+
+impl Step for KmerContamination {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[Transformation],
+    ) -> Result<()> {
+        if self.references.is_empty() {
+            bail!("FilterKmerContamination needs at least one reference file.");
+        }
+        let mut seen = HashSet::new();
+        for reference in &self.references {
+            if reference.ends_with(".bam") || reference.ends_with(".sam") {
+                bail!(
+                    "FilterKmerContamination references must be fasta (or fastq) files, not {reference}"
+                );
+            }
+            if !seen.insert(reference) {
+                bail!("FilterKmerContamination: reference {reference} is listed twice.");
+            }
+        }
+        if let Ok(target) = self.target.try_into() {
+            validate_target(target, input_def)?;
+        }
+        Ok(())
+    }
+
+    fn init(
+        &mut self,
+        _input_info: &InputInfo,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<DemultiplexInfo>> {
+        let mut kmers = HashMap::new();
+        for (idx, reference) in self.references.iter().enumerate() {
+            let kmers = &mut kmers;
+            let k = self.k;
+            crate::io::apply_to_read_sequences(
+                reference,
+                &mut |seq| {
+                    for_each_canonical_kmer(seq, k, |kmer| {
+                        kmers.entry(kmer).or_insert(idx);
+                    });
+                },
+                None,
+            )
+            .with_context(|| format!("Could not read reference {reference}"))?;
+        }
+        self.kmers = Arc::new(kmers);
+        self.hit_counts = vec![0; self.references.len()];
+        Ok(None)
+    }
+
+    // for the report
+    fn needs_serial(&self) -> bool {
+        true
+    }
+
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        let kmers = &self.kmers;
+        let hit_counts = &mut self.hit_counts;
+        let molecule_count = &mut self.molecule_count;
+        let mut is_hit = |seqs: &[&[u8]]| {
+            *molecule_count += 1;
+            let hit = kmer_contamination_hit(
+                kmers,
+                hit_counts.len(),
+                self.k,
+                self.min_kmers,
+                self.min_kmer_fraction,
+                seqs,
+            );
+            if let Some(reference) = hit {
+                hit_counts[reference] += 1;
+            }
+            let keep = hit.is_some();
+            match self.keep_or_remove {
+                KeepOrRemove::Keep => keep,
+                KeepOrRemove::Remove => !keep,
+            }
+        };
+        if let Ok(target) = self.target.try_into() {
+            apply_filter(target, &mut block, |read| is_hit(&[read.seq()]));
+        } else {
+            apply_filter_all(&mut block, |read1, read2, index1, index2| {
+                let mut seqs = vec![read1.seq()];
+                seqs.extend(
+                    [read2, index1, index2]
+                        .into_iter()
+                        .flatten()
+                        .map(crate::io::WrappedFastQRead::seq),
+                );
+                is_hit(&seqs)
+            });
+        }
+        (block, true)
+    }
+
+    fn report_label(&self) -> Option<String> {
+        Some(self.report_label.clone())
+    }
+
+    fn set_report_no(&mut self, report_no: usize) {
+        self.report_no = report_no;
+    }
+
+    fn finalize(
+        &mut self,
+        _output_prefix: &str,
+        _output_directory: &Path,
+        _demultiplex_info: &Demultiplexed,
+    ) -> Result<Option<FinalizeReportResult>> {
+        let mut contents = serde_json::Map::new();
+        contents.insert("molecule_count".to_string(), self.molecule_count.into());
+        contents.insert(
+            "contaminated".to_string(),
+            self.hit_counts.iter().sum::<usize>().into(),
+        );
+        let references: serde_json::Map<String, serde_json::Value> = self
+            .references
+            .iter()
+            .zip(&self.hit_counts)
+            .map(|(reference, count)| (reference.clone(), (*count).into()))
+            .collect();
+        contents.insert("references".to_string(), references.into());
+        Ok(Some(FinalizeReportResult {
+            report_no: self.report_no,
+            contents: serde_json::Value::Object(contents),
+        }))
+    }
+}
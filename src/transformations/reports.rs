@@ -18,7 +18,7 @@ use std::{
 const PHRED33OFFSET: u8 = 33;
 
 // phred score (33 sanger encoding) to probability of error
-// python: ([1.0] * 32 + [10**(q/-10) for q in range(0,256)])[:256]
+// python: ([1.0] * 33 + [10**(q/-10) for q in range(0,256)])[:256]
 #[allow(clippy::unreadable_literal)]
 #[allow(clippy::excessive_precision)]
 const Q_LOOKUP: [f64; 256] = [
@@ -55,6 +55,7 @@ const Q_LOOKUP: [f64; 256] = [
     1.0,
     1.0,
     1.0,
+    1.0,
     0.7943282347242815,
     0.6309573444801932,
     0.5011872336272722,
@@ -277,9 +278,18 @@ const Q_LOOKUP: [f64; 256] = [
     1e-22,
     7.943282347242789e-23,
     6.309573444801943e-23,
-    5.011872336272715e-23,
 ];
 
+/// Probability of a base call being wrong, from its (phred 33) quality score.
+pub(crate) fn error_probability(quality: u8) -> f64 {
+    Q_LOOKUP[quality as usize]
+}
+
+/// Sum of the per base error probabilities - the expected number of errors in a read.
+pub(crate) fn expected_errors(quality: &[u8]) -> f64 {
+    quality.iter().map(|q| error_probability(*q)).sum()
+}
+
 fn default_progress_n() -> usize {
     1_000_000
 }
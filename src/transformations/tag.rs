@@ -9,7 +9,7 @@ use crate::{
         deser::{iupac_from_string, u8_from_char_or_number, u8_from_string, u8_regex_from_string},
         Target, TargetPlusAll,
     },
-    dna::{iupac_find_best, Anchor, Hit, HitRegion, Hits},
+    dna::{align_iupac, iupac_find_best, Anchor, Hit, HitRegion, Hits},
     io,
     transformations::filter_tag_locations_all_targets,
     Demultiplexed,
@@ -80,6 +80,9 @@ pub struct ExtractIUPAC {
     label: String,
     #[serde(default)] // 0 is fine.
     max_mismatches: u8,
+    /// allow indels, with up to this many errors per query base
+    #[serde(default)]
+    max_error_rate: Option<f32>,
 }
 
 impl Step for ExtractIUPAC {
@@ -89,6 +92,7 @@ impl Step for ExtractIUPAC {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[super::Transformation],
     ) -> anyhow::Result<()> {
+        super::validate_max_error_rate(self.max_error_rate, self.max_mismatches > 0)?;
         super::validate_target(self.target, input_def)
     }
 
@@ -105,7 +109,17 @@ impl Step for ExtractIUPAC {
         extract_tags(
             self.target,
             &self.label,
-            |read| read.find_iupac(&self.search, self.anchor, self.max_mismatches, self.target),
+            |read| match self.max_error_rate {
+                Some(max_error_rate) => read.find_iupac_with_indels(
+                    &self.search,
+                    self.anchor,
+                    max_error_rate,
+                    self.target,
+                ),
+                None => {
+                    read.find_iupac(&self.search, self.anchor, self.max_mismatches, self.target)
+                }
+            },
             &mut block,
         );
 
@@ -193,44 +207,15 @@ pub struct ExtractAnchor {
     pub region_separator: Vec<u8>,
     #[serde(default)]
     max_mismatches: usize,
+    /// allow indels in the anchor, with up to this many errors per anchor base
+    #[serde(default)]
+    max_error_rate: Option<f32>,
     pub target: Target,
 
     label: String,
-    #[serde(skip)]
-    left_most: isize,
-    #[serde(skip)]
-    right_most: isize,
 }
 
 impl Step for ExtractAnchor {
-    fn init(
-        &mut self,
-        _input_info: &super::InputInfo,
-        _output_prefix: &str,
-        _output_directory: &Path,
-        _demultiplex_info: &Demultiplexed,
-    ) -> Result<Option<crate::demultiplex::DemultiplexInfo>> {
-        self.left_most = self
-            .regions
-            .iter()
-            .map(|(region_start, _region_len)| *region_start)
-            .min()
-            .unwrap(); // we have at least one region
-        self.right_most = self
-            .regions
-            .iter()
-            .map(|(region_start, region_len)| {
-                let region_len: isize = (*region_len)
-                    .try_into()
-                    .expect("region length > isize limit");
-                *region_start + region_len
-            }) // we validate
-            // below
-            .max()
-            .unwrap();
-        Ok(None)
-    }
-
     fn validate(
         &self,
         input_def: &crate::config::Input,
@@ -245,6 +230,7 @@ impl Step for ExtractAnchor {
                 bail!("ExtractAnchor requires regions with non-zero length. Found a region with length 0.");
             }
         }
+        super::validate_max_error_rate(self.max_error_rate, self.max_mismatches > 0)?;
         super::validate_target(self.target, input_def)
     }
 
@@ -258,39 +244,71 @@ impl Step for ExtractAnchor {
         _block_no: usize,
         _demultiplex_info: &Demultiplexed,
     ) -> (crate::io::FastQBlocksCombined, bool) {
+        let search_len = self.search.len() as isize;
         extract_tags(
             self.target,
             &self.label,
             |read| {
                 let seq = read.seq();
-                if let Some(anchor_pos) = iupac_find_best(&self.search, seq, self.max_mismatches) {
-                    let start = anchor_pos as isize + self.left_most;
-                    if start < 0 {
-                        return None;
+                let (anchor_pos, anchor_end) = match self.max_error_rate {
+                    Some(max_error_rate) => {
+                        let hit = align_iupac(&self.search, seq, Anchor::Anywhere, max_error_rate)?;
+                        (hit.start as isize, hit.end as isize)
                     }
-                    let stop = anchor_pos as isize + self.right_most as isize;
-                    if stop > seq.len() as isize {
-                        return None;
+                    None => {
+                        let anchor_pos =
+                            iupac_find_best(&self.search, seq, self.max_mismatches)? as isize;
+                        (anchor_pos, anchor_pos + search_len)
                     }
-                    assert!(stop > start);
-                    let len = stop - start;
-
-                    let mut replacement: Vec<u8> = Vec::new();
-                    let mut first = true;
-                    for (region_start, region_len) in &self.regions {
-                        if !first {
-                            replacement.extend(self.region_separator.iter());
-                        }
-                        first = false;
-                        let absolute_region_start = (anchor_pos as isize + region_start) as usize;
-                        let absolute_region_end = absolute_region_start + region_len;
-                        //willst be within read.seq() to the left_most, right_most checks above.
-                        replacement.extend(&seq[absolute_region_start..absolute_region_end]);
+                };
+                // regions behind the anchor are relative to its end,
+                // which only differs from anchor_pos + search_len if the anchor contained indels
+                let to_read_pos = |relative_pos: isize| {
+                    if relative_pos >= search_len {
+                        anchor_end + relative_pos - search_len
+                    } else {
+                        anchor_pos + relative_pos
                     }
-                    Some(Hits::new(start as usize, len as usize, self.target, replacement))
-                } else {
-                    None
+                };
+                let absolute_regions: Vec<(isize, isize)> = self
+                    .regions
+                    .iter()
+                    .map(|(region_start, region_len)| {
+                        let absolute_region_start = to_read_pos(*region_start);
+                        (
+                            absolute_region_start,
+                            absolute_region_start + *region_len as isize,
+                        )
+                    })
+                    .collect();
+                let start = absolute_regions.iter().map(|(start, _)| *start).min()?; // we have at least one region
+                if start < 0 {
+                    return None;
                 }
+                let stop = absolute_regions.iter().map(|(_, stop)| *stop).max()?;
+                if stop > seq.len() as isize {
+                    return None;
+                }
+                assert!(stop > start);
+                let len = stop - start;
+
+                let mut replacement: Vec<u8> = Vec::new();
+                let mut first = true;
+                for (absolute_region_start, absolute_region_end) in absolute_regions {
+                    if !first {
+                        replacement.extend(self.region_separator.iter());
+                    }
+                    first = false;
+                    //willst be within read.seq() due to the start / stop checks above.
+                    replacement
+                        .extend(&seq[absolute_region_start as usize..absolute_region_end as usize]);
+                }
+                Some(Hits::new(
+                    start as usize,
+                    len as usize,
+                    self.target,
+                    replacement,
+                ))
             },
             &mut block,
         );
@@ -1061,6 +1079,61 @@ impl Step for ExtractLength {
     }
 }
 
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExtractExpectedErrors {
+    label: String,
+    pub target: Target,
+    #[serde(default)]
+    per_base: bool,
+}
+
+impl Step for ExtractExpectedErrors {
+    fn validate(
+        &self,
+        input_def: &crate::config::Input,
+        _output_def: Option<&crate::config::Output>,
+        _all_transforms: &[super::Transformation],
+    ) -> anyhow::Result<()> {
+        super::validate_target(self.target, input_def)
+    }
+
+    fn sets_tag(&self) -> Option<String> {
+        Some(self.label.clone())
+    }
+
+    fn tag_provides_location(&self) -> bool {
+        false
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn apply(
+        &mut self,
+        mut block: crate::io::FastQBlocksCombined,
+        _block_no: usize,
+        _demultiplex_info: &Demultiplexed,
+    ) -> (crate::io::FastQBlocksCombined, bool) {
+        extract_tags(
+            self.target,
+            &self.label,
+            |read| {
+                let qual = read.qual();
+                let mut errors = super::reports::expected_errors(qual);
+                if self.per_base && !qual.is_empty() {
+                    errors /= qual.len() as f64;
+                }
+                Some(Hits::new_multiple(vec![Hit {
+                    location: None,
+                    sequence: format!("{errors:.6}").into_bytes(),
+                }]))
+            },
+            &mut block,
+        );
+
+        (block, true)
+    }
+}
+
 #[derive(serde::Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct RemoveTag {
@@ -1090,7 +1163,8 @@ impl Step for RemoveTag {
 pub struct StoreTagsInTable {
     table_filename: String,
     #[serde(default)]
-    compression: crate::config::FileFormat,
+    compression: crate::config::CompressionFormat,
+    compression_level: Option<u8>,
 
     #[serde(default = "default_region_separator")]
     #[serde(deserialize_with = "u8_from_string")]
@@ -1109,6 +1183,7 @@ impl std::fmt::Debug for StoreTagsInTable {
         f.debug_struct("StoreTagsInTable")
             .field("table_filename", &self.table_filename)
             .field("compression", &self.compression)
+            .field("compression_level", &self.compression_level)
             .field("region_separator", &self.region_separator)
             .finish()
     }
@@ -1119,6 +1194,7 @@ impl Clone for StoreTagsInTable {
         Self {
             table_filename: self.table_filename.clone(),
             compression: self.compression,
+            compression_level: self.compression_level,
             region_separator: self.region_separator.clone(),
             full_output_path: self.full_output_path.clone(),
             output_handle: None,
@@ -1134,9 +1210,7 @@ impl Step for StoreTagsInTable {
         _output_def: Option<&crate::config::Output>,
         _all_transforms: &[Transformation],
     ) -> Result<()> {
-        if matches!(self.compression, crate::config::FileFormat::None) {
-            bail!("StoreTagsInTable doesn't support 'None' for 'no output'. Use 'raw' to get uncompressed data.");
-        }
+        crate::config::validate_compression_level(self.compression, self.compression_level)?;
         Ok(())
     }
 
@@ -1171,6 +1245,11 @@ impl Step for StoreTagsInTable {
                 let buffered_writer = crate::open_output_file(
                     self.full_output_path.as_ref().unwrap(),
                     self.compression,
+                    crate::CompressionSettings {
+                        level: self.compression_level,
+                        zstd_long: false,
+                        threads: crate::CompressionThreads::default(),
+                    },
                 )
                 .expect("Failed to open table output file");
                 let writer = csv::WriterBuilder::new()